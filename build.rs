@@ -1,4 +1,21 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use xmas_elf::{
+    sections::SectionData,
+    symbol_table::{Entry, Type},
+    ElfFile,
+};
+
+/// The section the kernel reserves for its symbol table, see
+/// `kernel::debug::symbols`.
+const SYMBOL_TABLE_SECTION: &str = ".ksymtab";
+
+/// The magic value at the start of a filled symbol table.
+const SYMBOL_TABLE_MAGIC: &[u8; 8] = b"KSYMTAB1";
+
+/// The size of the symbol table header and of each entry.
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 24;
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -8,6 +25,9 @@ fn main() {
     let kernel =
         PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL").unwrap());
 
+    // give the kernel its own symbols, for backtraces
+    let kernel = embed_symbol_table(&kernel, &out_dir);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
@@ -24,3 +44,90 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Write a copy of the kernel with its function symbols filled into the
+/// section it reserves for them, and return the path of the copy.
+///
+/// The table is laid out as
+///
+/// ```text
+/// | magic | address of the table | count | entries ... | names ... |
+/// ```
+///
+/// with one `| address | size | name offset | name length |` entry per
+/// function, sorted by address. All fields are little endian, the first
+/// three are 64 bit wide, as are the address and size of an entry, the name
+/// offset and length are 32 bit wide. Names are demangled and without hash.
+/// The kernel compares the address of the table with its runtime address to
+/// find out where it was loaded.
+fn embed_symbol_table(kernel: &Path, out_dir: &Path) -> PathBuf {
+    let mut image = std::fs::read(kernel).unwrap();
+    let elf = ElfFile::new(&image).unwrap();
+
+    let Some(section) = elf.find_section_by_name(SYMBOL_TABLE_SECTION) else {
+        println!("cargo:warning=kernel has no symbol table section");
+        return kernel.to_path_buf();
+    };
+    let table_offset = section.offset() as usize;
+    let table_size = section.size() as usize;
+    let table_address = section.address();
+
+    let mut symbols: Vec<(u64, u64, String)> = Vec::new();
+    if let Some(symtab) = elf.find_section_by_name(".symtab") {
+        if let Ok(SectionData::SymbolTable64(entries)) = symtab.get_data(&elf) {
+            for entry in entries {
+                if entry.get_type() != Ok(Type::Func) || entry.size() == 0 {
+                    continue;
+                }
+                let Ok(name) = entry.get_name(&elf) else {
+                    continue;
+                };
+                let name = format!("{:#}", rustc_demangle::demangle(name));
+                symbols.push((entry.value(), entry.size(), name));
+            }
+        }
+    }
+    symbols.sort_by_key(|&(address, ..)| address);
+    symbols.dedup_by_key(|&mut (address, ..)| address);
+
+    // keep as many symbols as fit, the missing ones are printed as addresses
+    let mut used = HEADER_SIZE;
+    let count = symbols
+        .iter()
+        .take_while(|(.., name)| {
+            used += ENTRY_SIZE + name.len();
+            used <= table_size
+        })
+        .count();
+    if count < symbols.len() {
+        println!(
+            "cargo:warning=kernel symbol table full, {} of {} symbols \
+             omitted",
+            symbols.len() - count,
+            symbols.len()
+        );
+    }
+    let symbols = &symbols[..count];
+
+    let mut table = Vec::with_capacity(table_size);
+    table.extend_from_slice(SYMBOL_TABLE_MAGIC);
+    table.extend_from_slice(&table_address.to_le_bytes());
+    table.extend_from_slice(&(symbols.len() as u64).to_le_bytes());
+    let mut name_offset = HEADER_SIZE + ENTRY_SIZE * symbols.len();
+    for (address, size, name) in symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(name_offset as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        name_offset += name.len();
+    }
+    for (.., name) in symbols {
+        table.extend_from_slice(name.as_bytes());
+    }
+    assert!(table.len() <= table_size);
+
+    image[table_offset..table_offset + table.len()].copy_from_slice(&table);
+    let path = out_dir.join("kernel");
+    std::fs::write(&path, image).unwrap();
+    path
+}
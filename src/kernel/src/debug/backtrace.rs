@@ -0,0 +1,103 @@
+//! Stack backtraces
+//!
+//! Walks the frame pointer chain, which requires the kernel to be built with
+//! frame pointers (see `.cargo/config.toml`). Every frame is checked to be
+//! mapped before it is read, so a corrupted chain ends the walk instead of
+//! faulting. Return addresses are symbolized with [`super::symbols`].
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use super::symbols;
+use crate::mm::paging;
+
+/// The maximum number of frames walked, in case the chain loops.
+const MAX_FRAMES: usize = 64;
+
+/// Call `f` with the return address of every frame in the frame pointer
+/// chain starting at `frame_pointer`, innermost first.
+///
+/// Takes no locks and does not allocate.
+///
+/// # Arguments
+/// * `frame_pointer` - The frame pointer (`rbp`) of the innermost frame
+/// * `f` - Called with each return address
+pub fn walk(frame_pointer: u64, mut f: impl FnMut(u64)) {
+    let mut frame_pointer = frame_pointer;
+    for _ in 0..MAX_FRAMES {
+        // a frame is the saved frame pointer followed by the return address
+        if frame_pointer == 0
+            || frame_pointer % 8 != 0
+            || !is_readable(frame_pointer)
+            || !is_readable(frame_pointer + 8)
+        {
+            break;
+        }
+        let frame = frame_pointer as *const u64;
+        let (next, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        f(return_address);
+        if next == frame_pointer {
+            break;
+        }
+        frame_pointer = next;
+    }
+}
+
+/// Returns `true` if the 8 bytes at `addr` can be read without faulting.
+fn is_readable(addr: u64) -> bool {
+    VirtAddr::try_new(addr).is_ok_and(|addr| paging::walk(addr).is_mapped())
+}
+
+/// Returns the frame pointer of the caller.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let frame_pointer: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack))
+    };
+    frame_pointer
+}
+
+/// Log a backtrace of the calling code.
+///
+/// # Arguments
+/// * `level` - The log level to use
+#[inline(always)]
+pub fn log_backtrace(level: log::Level) {
+    log_backtrace_from(frame_pointer(), level);
+}
+
+/// Log a backtrace of the frame pointer chain starting at `frame_pointer`.
+///
+/// # Arguments
+/// * `frame_pointer` - The frame pointer of the innermost frame
+/// * `level` - The log level to use
+pub fn log_backtrace_from(frame_pointer: u64, level: log::Level) {
+    log::log!(level, "Backtrace:");
+    let mut depth = 0;
+    walk(frame_pointer, |return_address| {
+        // the return address points after the call, which may be the first
+        // byte of the next function
+        match symbols::lookup(return_address - 1) {
+            Some(symbol) => log::log!(
+                level,
+                "  {depth:>2}: {return_address:#018x} {}+{:#x}",
+                symbol.name,
+                return_address - symbol.address
+            ),
+            None => {
+                log::log!(
+                    level,
+                    "  {depth:>2}: {return_address:#018x} <unknown>"
+                )
+            }
+        }
+        depth += 1;
+    });
+    if depth == 0 {
+        log::log!(level, "  <empty>");
+    }
+}
@@ -0,0 +1,248 @@
+//! A line based debug console on the keyboard
+//!
+//! Reads lines from the keyboard and runs the matching command. Output goes
+//! to the log, so it shows up on the framebuffer and the serial port.
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+
+use futures_util::stream::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::VirtAddr;
+
+use crate::{
+    devices::keyboard::ScancodeStream,
+    drivers::apic::io_apic,
+    interrupts::{irq, stats},
+    mm::{allocator, frame_allocator::FRAME_ALLOCATOR, paging, Locked},
+    usermode,
+};
+
+/// The log level console output is written with. The logger drops anything
+/// less severe than warnings.
+pub const OUTPUT_LEVEL: log::Level = log::Level::Warn;
+
+/// The longest line the console accepts.
+const MAX_LINE_LENGTH: usize = 128;
+
+/// A console command.
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    /// The name the command is invoked with.
+    pub name: &'static str,
+    /// A one line description, shown by `help`.
+    pub help: &'static str,
+    /// Runs the command with the words following its name.
+    pub run: fn(&[&str]),
+}
+
+/// The commands every console has.
+const BUILTIN_COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "translate",
+        help: "translate <addr>: walk the page table for a virtual address",
+        run: translate,
+    },
+    Command {
+        name: "mappings",
+        help: "dump all present mappings of the active page table",
+        run: mappings,
+    },
+    Command {
+        name: "memory",
+        help: "show physical memory and heap usage",
+        run: memory,
+    },
+    #[cfg(feature = "heap-tracking")]
+    Command {
+        name: "allocations",
+        help: "allocations [min_age]: group live heap allocations by call site",
+        run: allocations,
+    },
+    Command {
+        name: "irqs",
+        help: "list the registered interrupt handlers",
+        run: irqs,
+    },
+    Command {
+        name: "interrupts",
+        help: "show the interrupt counters per vector and CPU",
+        run: interrupts,
+    },
+    Command {
+        name: "ioapics",
+        help: "list the I/O APICs and their unmasked inputs",
+        run: ioapics,
+    },
+    Command {
+        name: "usermode",
+        help: "run test programs in ring 3 and show how they returned",
+        run: usermode,
+    },
+];
+
+/// Commands registered by other subsystems.
+static COMMANDS: Locked<Vec<Command>> = Locked::new(Vec::new());
+
+/// Register an additional console command.
+///
+/// # Arguments
+/// * `command` - The command to add. Replaces a registered command with the
+///   same name.
+pub fn register_command(command: Command) {
+    let mut commands = COMMANDS.lock();
+    commands.retain(|other| other.name != command.name);
+    commands.push(command);
+}
+
+/// Run the console, reading lines from the keyboard forever.
+///
+/// # Example
+/// ```no_run
+/// let mut executor = executor::Executor::new();
+/// executor.spawn(Task::new(debug::console::run()));
+/// executor.run();
+/// ```
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::Ignore,
+    );
+    let mut line = String::new();
+
+    while let Some(scancode) = scancodes.next().await {
+        let Ok(Some(key_event)) = keyboard.add_byte(scancode) else {
+            continue;
+        };
+        match keyboard.process_keyevent(key_event) {
+            Some(DecodedKey::Unicode('\n')) => {
+                log::log!(OUTPUT_LEVEL, "> {line}");
+                execute(&line);
+                line.clear();
+            }
+            Some(DecodedKey::Unicode('\u{8}')) => {
+                line.pop();
+            }
+            Some(DecodedKey::Unicode(character))
+                if !character.is_control() && line.len() < MAX_LINE_LENGTH =>
+            {
+                line.push(character);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Run a single command line.
+///
+/// # Arguments
+/// * `line` - The command name followed by its arguments.
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return;
+    };
+
+    let registered = COMMANDS.lock().iter().find(|c| c.name == name).copied();
+    let command = BUILTIN_COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .copied()
+        .or(registered);
+    match command {
+        Some(command) => (command.run)(args),
+        None => log::log!(OUTPUT_LEVEL, "unknown command {name:?}, try help"),
+    }
+}
+
+/// List the available commands.
+fn help(_args: &[&str]) {
+    let registered = COMMANDS.lock().clone();
+    for command in BUILTIN_COMMANDS.iter().chain(registered.iter()) {
+        log::log!(OUTPUT_LEVEL, "  {:<12} {}", command.name, command.help);
+    }
+}
+
+/// Walk the page table for the address given as the first argument.
+fn translate(args: &[&str]) {
+    let Some(addr) = args.first().and_then(|arg| parse_address(arg)) else {
+        log::log!(OUTPUT_LEVEL, "usage: translate <addr>");
+        return;
+    };
+    match VirtAddr::try_new(addr) {
+        Ok(addr) => paging::log_translation(addr, OUTPUT_LEVEL),
+        Err(_) => log::log!(OUTPUT_LEVEL, "{addr:#x} is not canonical"),
+    }
+}
+
+/// Dump all present mappings.
+fn mappings(_args: &[&str]) {
+    paging::log_mappings(OUTPUT_LEVEL);
+}
+
+/// Show physical memory and heap usage.
+fn memory(_args: &[&str]) {
+    let (free, usable) = {
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        (
+            frame_allocator.free_frames(),
+            frame_allocator.usable_frames(),
+        )
+    };
+    log::log!(OUTPUT_LEVEL, "frames: {free} of {usable} free");
+    allocator::log_heap_stats(OUTPUT_LEVEL);
+}
+
+/// Show the live heap allocations that are at least the given number of
+/// ticks old, grouped by call site.
+#[cfg(feature = "heap-tracking")]
+fn allocations(args: &[&str]) {
+    let min_age = match args {
+        [] => Some(0),
+        [age] => age.parse().ok(),
+        _ => None,
+    };
+    let Some(min_age) = min_age else {
+        log::log!(OUTPUT_LEVEL, "usage: allocations [min_age]");
+        return;
+    };
+    crate::mm::heap_tracking::log_live_allocations(min_age, OUTPUT_LEVEL);
+}
+
+/// List the registered interrupt handlers.
+fn irqs(_args: &[&str]) {
+    irq::log_handlers(OUTPUT_LEVEL);
+}
+
+/// Show the interrupt counters.
+fn interrupts(_args: &[&str]) {
+    stats::log_stats(OUTPUT_LEVEL);
+}
+
+/// List the I/O APICs.
+fn ioapics(_args: &[&str]) {
+    io_apic::log_io_apics(OUTPUT_LEVEL);
+}
+
+/// Run the user mode test programs.
+fn usermode(_args: &[&str]) {
+    usermode::run_test(OUTPUT_LEVEL);
+}
+
+/// Parse a hexadecimal address, with or without `0x` prefix and `_`
+/// separators.
+fn parse_address(arg: &str) -> Option<u64> {
+    let digits: String = arg
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|&c| c != '_')
+        .collect();
+    u64::from_str_radix(&digits, 16).ok()
+}
@@ -0,0 +1,4 @@
+//! Debugging facilities
+pub mod backtrace;
+pub mod console;
+pub mod symbols;
@@ -0,0 +1,131 @@
+//! Kernel symbol table
+//!
+//! The kernel reserves a section for a table of its own function symbols,
+//! which the build script fills in after linking (see `embed_symbol_table` in
+//! the top-level `build.rs` for the layout). A kernel that was not
+//! post-processed, for example one started directly by a test runner, has an
+//! empty table and addresses are printed without symbols.
+use core::{fmt, hint, str};
+
+/// The size reserved for the symbol table.
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+
+/// The magic value at the start of a filled symbol table.
+const SYMBOL_TABLE_MAGIC: &[u8; 8] = b"KSYMTAB1";
+
+/// The size of the symbol table header and of each entry.
+const HEADER_SIZE: usize = 24;
+const ENTRY_SIZE: usize = 24;
+
+/// The storage for the symbol table.
+#[repr(C, align(8))]
+struct SymbolTableStorage([u8; SYMBOL_TABLE_SIZE]);
+
+/// The symbol table, filled in by the build script.
+#[used]
+#[link_section = ".ksymtab"]
+static SYMBOL_TABLE: SymbolTableStorage =
+    SymbolTableStorage([0; SYMBOL_TABLE_SIZE]);
+
+/// A function symbol.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// The demangled name of the function.
+    pub name: &'static str,
+    /// The runtime address of the first instruction of the function.
+    pub address: u64,
+    /// The size of the function in bytes.
+    pub size: u64,
+}
+
+/// An address together with the symbol it lies in, if any. Displays as
+/// `0x<address> <symbol>+0x<offset>`.
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match lookup(self.0) {
+            Some(symbol) => {
+                write!(f, " {}+{:#x}", symbol.name, self.0 - symbol.address)
+            }
+            None => write!(f, " <unknown>"),
+        }
+    }
+}
+
+/// Returns the bytes of the symbol table.
+fn table() -> &'static [u8] {
+    // the compiler only sees the zeros the table is initialized with, so it
+    // must not be allowed to assume its contents
+    let table: &'static SymbolTableStorage = hint::black_box(&SYMBOL_TABLE);
+    &table.0
+}
+
+/// Reads a little endian `u64` at `offset` of the table.
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(table[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads a little endian `u32` at `offset` of the table.
+fn read_u32(table: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap())
+}
+
+/// Returns the number of symbols and the distance between the runtime and
+/// the link time addresses of the kernel, or `None` if the table is empty.
+fn header() -> Option<(usize, u64)> {
+    let table = table();
+    if &table[..8] != SYMBOL_TABLE_MAGIC {
+        return None;
+    }
+    let link_address = read_u64(table, 8);
+    let count = read_u64(table, 16) as usize;
+    let load_offset = (table.as_ptr() as u64).wrapping_sub(link_address);
+    Some((count, load_offset))
+}
+
+/// Returns `true` if the kernel was built with a symbol table.
+pub fn is_available() -> bool {
+    header().is_some()
+}
+
+/// Returns the symbol at `index`, with its runtime address.
+fn symbol(index: usize, load_offset: u64) -> Symbol {
+    let table = table();
+    let entry = HEADER_SIZE + index * ENTRY_SIZE;
+    let name_offset = read_u32(table, entry + 16) as usize;
+    let name_len = read_u32(table, entry + 20) as usize;
+    Symbol {
+        name: str::from_utf8(&table[name_offset..name_offset + name_len])
+            .unwrap_or("<invalid name>"),
+        address: read_u64(table, entry).wrapping_add(load_offset),
+        size: read_u64(table, entry + 8),
+    }
+}
+
+/// Find the function containing an address.
+///
+/// Takes no locks and does not allocate, so it can be used while panicking.
+///
+/// # Arguments
+/// * `addr` - The runtime address to look up
+///
+/// # Returns
+/// The symbol of the function, or `None` if the address does not lie in a
+/// known function.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let (count, load_offset) = header()?;
+    // the index of the first symbol starting after the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if symbol(middle, load_offset).address <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let symbol = symbol(low.checked_sub(1)?, load_offset);
+    (addr - symbol.address < symbol.size).then_some(symbol)
+}
@@ -1,12 +1,13 @@
 //! Keyboard Utilities
+extern crate alloc;
 
+use alloc::collections::VecDeque;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
 use futures_util::{
     stream::{Stream, StreamExt},
     task::AtomicWaker,
@@ -14,42 +15,58 @@ use futures_util::{
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use x86_64::structures::idt::InterruptStackFrame;
 
-use crate::drivers::apic::{self, registers::APICRegisters};
+use crate::{
+    drivers::apic::registers::APICRegisters,
+    interrupts::{irq, InterruptIndex},
+    mm::{mmio::Mmio, Locked},
+    task::deferred,
+};
+
+/// The maximum number of scancodes waiting to be read.
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+/// Scancodes waiting to be read. Only used from task context, so interrupts
+/// do not need to be disabled while it is locked.
+static SCANCODE_QUEUE: Locked<VecDeque<u8>> = Locked::new(VecDeque::new());
+static STREAM_CREATED: AtomicBool = AtomicBool::new(false);
+/// Scancodes the interrupt handler could not defer, reported by
+/// [`add_scancode`].
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
 static WAKER: AtomicWaker = AtomicWaker::new();
 
 /// Initialize the keyboard
 ///
-/// # Safety
-/// This function directly writes to memory-mapped Local APIC registers.
-///
 /// # Arguments
-/// * `local_apic_ptr` - A pointer to the Local APIC registers
-pub unsafe fn init(local_apic_ptr: *mut u32) {
-    let keyboard_register =
-        local_apic_ptr.offset(APICRegisters::LvtLint1 as isize / 4);
-    keyboard_register.write_volatile(
-        crate::interrupts::InterruptIndex::Keyboard as u8 as u32,
+/// * `local_apic` - The mapped Local APIC registers
+pub fn init(local_apic: &Mmio) {
+    irq::register(InterruptIndex::Keyboard as u8, "keyboard", keyboard_handler)
+        .expect("failed to register the keyboard handler");
+
+    local_apic.write::<u32>(
+        APICRegisters::LvtLint1 as usize,
+        InterruptIndex::Keyboard as u32,
     );
 }
 
-/// Called by the keyboard interrupt handler (must not block or allocate)
+/// Called as deferred work of the keyboard interrupt handler
 ///
 /// Adds a scancode to the scancode queue
 ///
 /// # Arguments
 /// * `scancode` - The scancode received from the keyboard
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if queue.push(scancode).is_err() {
-            log::warn!("scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
-    } else {
-        log::warn!("WARNING: scancode queue uninitialized");
+    let dropped = DROPPED_SCANCODES.swap(0, Ordering::Relaxed);
+    if dropped != 0 {
+        log::warn!("dropped {dropped} scancodes that could not be deferred");
+    }
+
+    let mut queue = SCANCODE_QUEUE.lock();
+    if queue.len() >= SCANCODE_QUEUE_CAPACITY {
+        log::warn!("scancode queue full; dropping keyboard input");
+        return;
     }
+    queue.push_back(scancode);
+    WAKER.wake();
 }
 
 /// A stream of scancodes
@@ -59,12 +76,10 @@ pub struct ScancodeStream {
 
 impl ScancodeStream {
     /// Create a new ScancodeStream (should only be called once)
-    ///
-    /// initializes the scancode queue with [`ArrayQueue`](https://docs.rs/crossbeam/latest/crossbeam/queue/struct.ArrayQueue.html)
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+        if STREAM_CREATED.swap(true, Ordering::Relaxed) {
+            panic!("ScancodeStream::new should only be called once");
+        }
         ScancodeStream { _private: () }
     }
 }
@@ -80,19 +95,14 @@ impl Stream for ScancodeStream {
     /// # Arguments
     /// * `cx` - The current task's context
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        // try to get the scancode queue
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
-
         // fast path
-        if let Some(scancode) = queue.pop() {
+        if let Some(scancode) = SCANCODE_QUEUE.lock().pop_front() {
             return Poll::Ready(Some(scancode));
         }
 
         // slow path
         WAKER.register(cx.waker());
-        match queue.pop() {
+        match SCANCODE_QUEUE.lock().pop_front() {
             Some(scancode) => {
                 WAKER.take();
                 Poll::Ready(Some(scancode))
@@ -137,19 +147,21 @@ pub async fn print_keypresses() {
 
 /// Keyboard interrupt handler
 ///
-/// reads the scancode from the keyboard port and adds it to the scancode queue
+/// reads the scancode from the keyboard port and defers adding it to the
+/// scancode queue
 ///
 /// # Arguments
 /// * `_stack_frame` - The interrupt stack frame
-pub extern "x86-interrupt" fn keyboard_handler(
-    _stack_frame: InterruptStackFrame,
-) {
+fn keyboard_handler(_stack_frame: &InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
-    crate::devices::keyboard::add_scancode(scancode);
-
-    apic::end_interrupt();
+    // no logging here, the interrupted code may hold the logger lock
+    if deferred::defer(|scancode| add_scancode(scancode as u8), scancode.into())
+        .is_err()
+    {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    }
 }
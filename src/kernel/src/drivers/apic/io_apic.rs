@@ -1,27 +1,540 @@
 //! I/O APIC (Advanced Programmable Interrupt Controller) module
-use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+//!
+//! The I/O APIC routes external interrupt lines, numbered as global system
+//! interrupts (GSIs), to a vector on a local APIC. Its registers are reached
+//! indirectly: the register index is written to `IOREGSEL`, then the
+//! register is read or written through `IOWIN`. Each line has a 64 bit
+//! redirection entry made of two registers.
+//!
+//! A machine can have several I/O APICs, each connected to a consecutive
+//! range of GSIs starting at its GSI base. They are kept in a registry keyed
+//! by GSI base, and [`route`], [`mask`] and [`unmask`] pick the I/O APIC
+//! whose range contains the GSI.
+//!
+//! Legacy ISA IRQs are connected to the GSI of the same number unless the
+//! MADT has an interrupt source override for them, so they are routed with
+//! [`route_isa_irq`], which applies the overrides.
+extern crate alloc;
 
-use super::map_apic;
+use alloc::collections::BTreeMap;
+use core::fmt;
 
-/// Initialize the I/O APIC
+use acpi::platform::interrupt::{
+    InterruptSourceOverride, IoApic as AcpiIoApic, Polarity as AcpiPolarity,
+    TriggerMode as AcpiTriggerMode,
+};
+use x86_64::PhysAddr;
+
+use crate::{
+    mm::{
+        mmio::{self, CachePolicy, Mmio, MmioError},
+        Locked,
+    },
+    sync::IrqSafeMutex,
+};
+
+/// The size of the I/O APIC register block.
+const IO_APIC_SIZE: usize = 0x20;
+
+/// The offset of the register select register.
+const IOREGSEL: usize = 0x00;
+
+/// The offset of the register window.
+const IOWIN: usize = 0x10;
+
+/// The ID register.
+const IOAPICID: u32 = 0x00;
+
+/// The version register, which also holds the highest redirection entry.
+const IOAPICVER: u32 = 0x01;
+
+/// The low half of the first redirection entry, entry `i` starts at
+/// `+ 2 * i`.
+const IOREDTBL: u32 = 0x10;
+
+/// The number of legacy ISA IRQs.
+const ISA_IRQ_COUNT: usize = 16;
+
+/// The registered I/O APICs, keyed by GSI base.
+static IO_APICS: IrqSafeMutex<BTreeMap<u32, IoApic>> =
+    IrqSafeMutex::new(BTreeMap::new());
+
+/// The GSI and signal of each legacy ISA IRQ, after applying the interrupt
+/// source overrides.
+static ISA_IRQS: Locked<[IsaIrq; ISA_IRQ_COUNT]> =
+    Locked::new(IsaIrq::IDENTITY);
+
+/// An error that occurred while programming the I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// No registered I/O APIC has an input connected to the GSI.
+    NoSuchGsi(u32),
+    /// The GSI range of an I/O APIC overlaps that of one registered before.
+    OverlappingGsis(u32),
+    /// The number is not a legacy ISA IRQ.
+    InvalidIsaIrq(u8),
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoApicError::NoSuchGsi(gsi) => {
+                write!(f, "no I/O APIC input for GSI {gsi}")
+            }
+            IoApicError::OverlappingGsis(gsi_base) => write!(
+                f,
+                "GSIs of the I/O APIC at GSI base {gsi_base} overlap another"
+            ),
+            IoApicError::InvalidIsaIrq(irq) => {
+                write!(f, "{irq} is not an ISA IRQ")
+            }
+        }
+    }
+}
+
+/// The level of an interrupt line that signals an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// How an interrupt line signals an interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Once, when the line becomes active.
+    Edge,
+    /// For as long as the line is active.
+    Level,
+}
+
+/// The GSI a legacy ISA IRQ is connected to and how it signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrq {
+    /// The global system interrupt.
+    pub gsi: u32,
+    /// The active level of the line.
+    pub polarity: Polarity,
+    /// The trigger mode of the line.
+    pub trigger_mode: TriggerMode,
+}
+
+impl IsaIrq {
+    /// The routing of every ISA IRQ without overrides: the GSI of the same
+    /// number, active high and edge triggered like the ISA bus.
+    const IDENTITY: [IsaIrq; ISA_IRQ_COUNT] = {
+        let mut irqs = [IsaIrq {
+            gsi: 0,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }; ISA_IRQ_COUNT];
+        let mut irq = 0;
+        while irq < ISA_IRQ_COUNT {
+            irqs[irq].gsi = irq as u32;
+            irq += 1;
+        }
+        irqs
+    };
+}
+
+/// A redirection entry: where an input is delivered and how it signals.
+///
+/// Interrupts are always delivered in fixed mode to a single local APIC in
+/// physical destination mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    /// The vector delivered to the local APIC.
+    pub vector: u8,
+    /// The ID of the local APIC to deliver to.
+    pub destination: u8,
+    /// The active level of the line.
+    pub polarity: Polarity,
+    /// The trigger mode of the line.
+    pub trigger_mode: TriggerMode,
+    /// Whether the line is masked.
+    pub masked: bool,
+}
+
+impl RedirectionEntry {
+    /// Decode a raw redirection entry.
+    fn from_raw(raw: u64) -> Self {
+        RedirectionEntry {
+            vector: raw as u8,
+            destination: (raw >> 56) as u8,
+            polarity: if raw & (1 << 13) != 0 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            },
+            trigger_mode: if raw & (1 << 15) != 0 {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+            masked: raw & (1 << 16) != 0,
+        }
+    }
+
+    /// Encode the entry. Bits 8 to 11 stay zero: fixed delivery mode,
+    /// physical destination mode.
+    fn to_raw(self) -> u64 {
+        let mut raw = self.vector as u64 | (self.destination as u64) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            raw |= 1 << 13;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            raw |= 1 << 15;
+        }
+        if self.masked {
+            raw |= 1 << 16;
+        }
+        raw
+    }
+}
+
+/// A mapped I/O APIC.
+///
+/// Every register access selects the register first, so an I/O APIC must
+/// only be used by one CPU at a time, e.g. through the registry.
+pub struct IoApic {
+    /// The register block.
+    registers: Mmio,
+    /// The first GSI connected to this I/O APIC.
+    gsi_base: u32,
+    /// The number of inputs, which is the number of redirection entries.
+    inputs: u32,
+}
+
+impl IoApic {
+    /// Map the I/O APIC at `address`.
+    ///
+    /// # Arguments
+    /// * `address` - The physical address of the register block
+    /// * `gsi_base` - The GSI connected to the first input
+    ///
+    /// # Safety
+    /// `address` must be the address of an I/O APIC.
+    pub unsafe fn new(
+        address: PhysAddr,
+        gsi_base: u32,
+    ) -> Result<Self, MmioError> {
+        let registers = unsafe {
+            mmio::map_mmio(address, IO_APIC_SIZE, CachePolicy::Uncached)
+        }?;
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((io_apic.read(IOAPICVER) >> 16) & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    /// Read the register with index `register`.
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write::<u32>(IOREGSEL, register);
+        self.registers.read::<u32>(IOWIN)
+    }
+
+    /// Write `value` to the register with index `register`.
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write::<u32>(IOREGSEL, register);
+        self.registers.write::<u32>(IOWIN, value);
+    }
+
+    /// Returns the APIC ID of the I/O APIC.
+    pub fn id(&self) -> u8 {
+        ((self.read(IOAPICID) >> 24) & 0xf) as u8
+    }
+
+    /// Returns the version of the I/O APIC.
+    pub fn version(&self) -> u8 {
+        self.read(IOAPICVER) as u8
+    }
+
+    /// Returns the first GSI connected to the I/O APIC.
+    pub fn gsi_base(&self) -> u32 {
+        self.gsi_base
+    }
+
+    /// Returns the number of inputs, one redirection entry each.
+    pub fn inputs(&self) -> u32 {
+        self.inputs
+    }
+
+    /// Returns the last GSI connected to the I/O APIC.
+    pub fn last_gsi(&self) -> u32 {
+        self.gsi_base + self.inputs - 1
+    }
+
+    /// Returns `true` if `gsi` is connected to this I/O APIC.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi.checked_sub(self.gsi_base)
+            .is_some_and(|input| input < self.inputs)
+    }
+
+    /// Returns the input `gsi` is connected to.
+    fn input(&self, gsi: u32) -> Result<u32, IoApicError> {
+        if !self.handles(gsi) {
+            return Err(IoApicError::NoSuchGsi(gsi));
+        }
+        Ok(gsi - self.gsi_base)
+    }
+
+    /// Read the redirection entry of `gsi`.
+    pub fn entry(&self, gsi: u32) -> Result<RedirectionEntry, IoApicError> {
+        let register = IOREDTBL + 2 * self.input(gsi)?;
+        let raw =
+            self.read(register) as u64 | (self.read(register + 1) as u64) << 32;
+        Ok(RedirectionEntry::from_raw(raw))
+    }
+
+    /// Write the redirection entry of `gsi`.
+    pub fn set_entry(
+        &self,
+        gsi: u32,
+        entry: RedirectionEntry,
+    ) -> Result<(), IoApicError> {
+        let register = IOREDTBL + 2 * self.input(gsi)?;
+        let raw = entry.to_raw();
+        // mask the line while the entry is half written
+        self.write(register, (1 << 16) as u32);
+        self.write(register + 1, (raw >> 32) as u32);
+        self.write(register, raw as u32);
+        Ok(())
+    }
+
+    /// Mask or unmask `gsi`, leaving the rest of its entry alone.
+    pub fn set_masked(
+        &self,
+        gsi: u32,
+        masked: bool,
+    ) -> Result<(), IoApicError> {
+        let entry = self.entry(gsi)?;
+        self.set_entry(gsi, RedirectionEntry { masked, ..entry })
+    }
+
+    /// Mask every input.
+    pub fn mask_all(&self) {
+        for input in 0..self.inputs {
+            let register = IOREDTBL + 2 * input;
+            self.write(register, self.read(register) | 1 << 16);
+        }
+    }
+}
+
+/// Map and register every I/O APIC and record the interrupt source
+/// overrides for [`route_isa_irq`].
+///
+/// I/O APICs that cannot be mapped or whose GSIs overlap another's are
+/// skipped with an error.
 ///
 /// # Arguments
-/// * `local_apic_addr` - The physical address of the I/O APIC
-/// * `mapper` - The mapper to use for mapping
-///   ([`x86_64::structures::paging::Mapper`])
-/// * `frame_allocator` - The frame allocator to use for allocating frames
-///  ([`x86_64::structures::paging::FrameAllocator`])
+/// * `io_apics` - The I/O APICs from the MADT
+/// * `overrides` - The interrupt source overrides from the MADT
+///
+/// # Safety
+/// The addresses must be those of the I/O APICs.
 pub unsafe fn init(
-    ioapic_address: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    io_apics: &[AcpiIoApic],
+    overrides: &[InterruptSourceOverride],
 ) {
-    let virt_addr = map_apic(ioapic_address as u64, mapper, frame_allocator);
+    for io_apic in io_apics {
+        let address = PhysAddr::new(io_apic.address as u64);
+        let gsi_base = io_apic.global_system_interrupt_base;
+        let result = match unsafe { IoApic::new(address, gsi_base) } {
+            Ok(io_apic) => register(io_apic),
+            Err(error) => {
+                log::error!(
+                    "failed to map the I/O APIC at {:#x}: {error:?}",
+                    address.as_u64()
+                );
+                continue;
+            }
+        };
+        if let Err(error) = result {
+            log::error!("skipping I/O APIC {}: {error}", io_apic.id);
+        }
+    }
+
+    let mut isa_irqs = ISA_IRQS.lock();
+    for source in overrides {
+        let Some(irq) = isa_irqs.get_mut(source.isa_source as usize) else {
+            continue;
+        };
+        irq.gsi = source.global_system_interrupt;
+        // "same as bus" is the ISA default
+        irq.polarity = match source.polarity {
+            AcpiPolarity::ActiveLow => Polarity::ActiveLow,
+            AcpiPolarity::ActiveHigh | AcpiPolarity::SameAsBus => {
+                Polarity::ActiveHigh
+            }
+        };
+        irq.trigger_mode = match source.trigger_mode {
+            AcpiTriggerMode::Level => TriggerMode::Level,
+            AcpiTriggerMode::Edge | AcpiTriggerMode::SameAsBus => {
+                TriggerMode::Edge
+            }
+        };
+        log::info!(
+            "ISA IRQ {} is GSI {} ({:?}, {:?})",
+            source.isa_source,
+            irq.gsi,
+            irq.polarity,
+            irq.trigger_mode
+        );
+    }
+}
 
-    let ioapic_pointer = virt_addr.as_mut_ptr::<u32>();
+/// Mask every input of an I/O APIC and add it to the registry.
+///
+/// # Arguments
+/// * `io_apic` - The mapped I/O APIC
+pub fn register(io_apic: IoApic) -> Result<(), IoApicError> {
+    let mut io_apics = IO_APICS.lock();
+    let overlaps = io_apics.values().any(|other| {
+        io_apic.gsi_base <= other.last_gsi()
+            && other.gsi_base <= io_apic.last_gsi()
+    });
+    if overlaps {
+        return Err(IoApicError::OverlappingGsis(io_apic.gsi_base));
+    }
 
-    ioapic_pointer.offset(0).write_volatile(0x12);
-    ioapic_pointer.offset(4).write_volatile(
-        crate::interrupts::InterruptIndex::Keyboard as u8 as u32,
+    io_apic.mask_all();
+    log::info!(
+        "I/O APIC {} version {:#x}: GSIs {} to {}",
+        io_apic.id(),
+        io_apic.version(),
+        io_apic.gsi_base,
+        io_apic.last_gsi()
     );
+    io_apics.insert(io_apic.gsi_base, io_apic);
+    Ok(())
+}
+
+/// Call `f` with the I/O APIC whose GSI range contains `gsi`.
+fn with_io_apic<T>(
+    gsi: u32,
+    f: impl FnOnce(&IoApic) -> Result<T, IoApicError>,
+) -> Result<T, IoApicError> {
+    let io_apics = IO_APICS.lock();
+    let (_, io_apic) = io_apics
+        .range(..=gsi)
+        .next_back()
+        .filter(|(_, io_apic)| io_apic.handles(gsi))
+        .ok_or(IoApicError::NoSuchGsi(gsi))?;
+    f(io_apic)
+}
+
+/// Log every registered I/O APIC and the routing of its unmasked inputs.
+///
+/// # Arguments
+/// * `level` - The log level to use
+pub fn log_io_apics(level: log::Level) {
+    let io_apics = IO_APICS.lock();
+    for io_apic in io_apics.values() {
+        log::log!(
+            level,
+            "I/O APIC {} version {:#x}: GSIs {} to {}",
+            io_apic.id(),
+            io_apic.version(),
+            io_apic.gsi_base,
+            io_apic.last_gsi()
+        );
+        for gsi in io_apic.gsi_base..=io_apic.last_gsi() {
+            let Ok(entry) = io_apic.entry(gsi) else {
+                continue;
+            };
+            if !entry.masked {
+                log::log!(
+                    level,
+                    "  GSI {gsi:>3} -> vector {:#04x} on APIC {} ({:?}, {:?})",
+                    entry.vector,
+                    entry.destination,
+                    entry.polarity,
+                    entry.trigger_mode
+                );
+            }
+        }
+    }
+}
+
+/// Route a GSI to a vector on a local APIC and unmask it.
+///
+/// # Arguments
+/// * `gsi` - The global system interrupt to route
+/// * `vector` - The vector to deliver
+/// * `destination` - The ID of the local APIC to deliver to
+/// * `polarity` - The active level of the line
+/// * `trigger_mode` - The trigger mode of the line
+pub fn route(
+    gsi: u32,
+    vector: u8,
+    destination: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) -> Result<(), IoApicError> {
+    let entry = RedirectionEntry {
+        vector,
+        destination,
+        polarity,
+        trigger_mode,
+        masked: false,
+    };
+    with_io_apic(gsi, |io_apic| io_apic.set_entry(gsi, entry))
+}
+
+/// Returns the GSI and signal of a legacy ISA IRQ.
+///
+/// # Arguments
+/// * `irq` - The ISA IRQ, from 0 to 15
+pub fn isa_irq(irq: u8) -> Result<IsaIrq, IoApicError> {
+    ISA_IRQS
+        .lock()
+        .get(irq as usize)
+        .copied()
+        .ok_or(IoApicError::InvalidIsaIrq(irq))
+}
+
+/// Route a legacy ISA IRQ to a vector on a local APIC and unmask it,
+/// honouring the interrupt source overrides.
+///
+/// # Arguments
+/// * `irq` - The ISA IRQ, from 0 to 15
+/// * `vector` - The vector to deliver
+/// * `destination` - The ID of the local APIC to deliver to
+pub fn route_isa_irq(
+    irq: u8,
+    vector: u8,
+    destination: u8,
+) -> Result<(), IoApicError> {
+    let isa_irq = isa_irq(irq)?;
+    route(
+        isa_irq.gsi,
+        vector,
+        destination,
+        isa_irq.polarity,
+        isa_irq.trigger_mode,
+    )
+}
+
+/// Mask a GSI.
+///
+/// # Arguments
+/// * `gsi` - The global system interrupt to mask
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, true)
+}
+
+/// Unmask a GSI routed with [`route`] or [`route_isa_irq`].
+///
+/// # Arguments
+/// * `gsi` - The global system interrupt to unmask
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, false)
+}
+
+/// Mask or unmask a GSI.
+fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    with_io_apic(gsi, |io_apic| io_apic.set_masked(gsi, masked))
 }
@@ -1,51 +1,45 @@
 //! Local APIC (Advanced Programmable Interrupt Controller) module
-use spin::{Lazy, Mutex};
-use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
 
-use super::map_apic;
+use super::registers::APICRegisters;
+use crate::{
+    mm::mmio::{self, CachePolicy, Mmio},
+    sync::IrqSafeMutex,
+};
 
-pub static LAPIC_ADDR: Lazy<Mutex<LocalAPICAddress>> =
-    Lazy::new(|| Mutex::new(LocalAPICAddress::new()));
+/// The size of the Local APIC register block.
+const LOCAL_APIC_SIZE: usize = 0x400;
 
-/// Local APIC address
-#[repr(C, packed)]
-pub struct LocalAPICAddress {
-    pub address: *mut u32,
-}
-
-impl LocalAPICAddress {
-    /// Create a new LocalAPICAddress
-    ///
-    /// sets the address to null pointer
-    pub fn new() -> Self {
-        Self {
-            address: core::ptr::null_mut(),
-        }
-    }
-}
-
-// single threaded environment
-unsafe impl Send for LocalAPICAddress {}
+/// The mapped Local APIC registers.
+pub static LOCAL_APIC: IrqSafeMutex<Option<Mmio>> = IrqSafeMutex::new(None);
 
 /// Initialize the local APIC
 ///
 /// # Arguments
 /// * `local_apic_addr` - The physical address of the Local APIC
-/// * `mapper` - The mapper to use for mapping
-///   ([`x86_64::structures::paging::Mapper`])
-/// * `frame_allocator` - The frame allocator to use for allocating frames
-///  ([`x86_64::structures::paging::FrameAllocator`])
-pub unsafe fn init_local_apic(
-    local_apic_addr: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    let virtual_address =
-        map_apic(local_apic_addr as u64, mapper, frame_allocator);
-
-    let local_apic_ptr = virtual_address.as_mut_ptr::<u32>();
-    LAPIC_ADDR.lock().address = local_apic_ptr;
+pub unsafe fn init_local_apic(local_apic_addr: usize) {
+    let local_apic = mmio::map_mmio(
+        PhysAddr::new(local_apic_addr as u64),
+        LOCAL_APIC_SIZE,
+        CachePolicy::Uncached,
+    )
+    .expect("Local APIC mapping failed");
+
+    // the timer fires right away, so the registers must be in place for
+    // `end_interrupt` before it is started. Interrupts stay disabled until
+    // the lock is released
+    let mut lapic = LOCAL_APIC.lock();
+    let lapic = lapic.insert(local_apic);
+    crate::devices::timer::init(lapic);
+    crate::devices::keyboard::init(lapic);
+}
 
-    crate::devices::timer::init(local_apic_ptr);
-    crate::devices::keyboard::init(local_apic_ptr);
+/// Returns the ID of the calling CPU's local APIC.
+///
+/// Returns `None` if the local APIC is not mapped yet or its registers are
+/// locked, so it never blocks and can be used in interrupt handlers.
+pub fn id() -> Option<u32> {
+    let lapic = LOCAL_APIC.try_lock()?;
+    let id = lapic.as_ref()?.read::<u32>(APICRegisters::Ir as usize);
+    Some(id >> 24)
 }
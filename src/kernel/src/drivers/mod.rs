@@ -1,14 +1,16 @@
 //! ACPI + APIC drivers
-use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Size4KiB},
-    VirtAddr,
-};
+use x86_64::VirtAddr;
+
+use crate::interrupts::InterruptIndex;
 
 pub mod acpi;
 pub mod apic;
 
 extern crate acpi as acpi_lib;
 
+/// The legacy ISA IRQ of the PS/2 keyboard.
+const KEYBOARD_ISA_IRQ: u8 = 1;
+
 /// Disable the legacy PIC (Programmable Interrupt Controller)
 pub fn disable_pic() {
     use x86_64::instructions::port::Port;
@@ -26,16 +28,7 @@ pub fn disable_pic() {
 /// * `rsdp` - The physical address of the RSDP
 /// * `physical_memory_offset` - The physical memory offset as a
 ///   [`x86_64::VirtAddr`]
-/// * `mapper` - The mapper to use for mapping
-///   ([`x86_64::structures::paging::Mapper`])
-/// * `frame_allocator` - The frame allocator to use for allocating frames
-///  ([`x86_64::structures::paging::FrameAllocator`])
-pub unsafe fn init(
-    rsdp: usize,
-    physical_memory_offset: VirtAddr,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+pub unsafe fn init(rsdp: usize, physical_memory_offset: VirtAddr) {
     let acpi = acpi::ACPI::new(physical_memory_offset);
 
     let acpi_tables = acpi_lib::AcpiTables::from_rsdp(acpi, rsdp)
@@ -47,15 +40,21 @@ pub unsafe fn init(
 
     match platform_info.interrupt_model {
         acpi_lib::InterruptModel::Apic(apic) => {
-            let io_apic_addr = apic.io_apics[0].address;
-            apic::io_apic::init(io_apic_addr as usize, mapper, frame_allocator);
+            apic::io_apic::init(
+                &apic.io_apics,
+                &apic.interrupt_source_overrides,
+            );
 
             let local_apic_addr = apic.local_apic_address;
-            apic::local_apic::init_local_apic(
-                local_apic_addr as usize,
-                mapper,
-                frame_allocator,
-            );
+            apic::local_apic::init_local_apic(local_apic_addr as usize);
+
+            let destination = apic::local_apic::id().unwrap_or(0) as u8;
+            apic::io_apic::route_isa_irq(
+                KEYBOARD_ISA_IRQ,
+                InterruptIndex::Keyboard as u8,
+                destination,
+            )
+            .expect("failed to route the keyboard interrupt");
         }
         _ => {
             panic!("Unsupported interrupt model");
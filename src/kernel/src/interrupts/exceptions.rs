@@ -0,0 +1,527 @@
+//! CPU exception handlers
+//!
+//! Every architectural exception gets a handler so that a fault is reported
+//! with its real cause instead of escalating to a double fault. Each report
+//! logs the decoded error code and a register dump, then panics. Exceptions
+//! raised in user mode return to the kernel instead (see
+//! [`crate::usermode`]), except for machine checks. Debug exceptions are
+//! only logged, and NMIs are recorded and reported later from the deferred
+//! work task (see [`defer_nmi_report`]). The breakpoint, double fault and page
+//! fault handlers live in the parent module.
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+use x86_64::{
+    instructions::port::Port,
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        model_specific::Msr,
+    },
+    structures::idt::{
+        DescriptorTable, ExceptionVector, InterruptDescriptorTable,
+        InterruptStackFrame, SelectorErrorCode,
+    },
+};
+
+use super::{gdt, stats};
+use crate::{
+    debug::symbols::Symbolized,
+    sync::InterruptContext,
+    task::deferred,
+    usermode::{self, UserExit},
+};
+
+/// The number of NMIs that have not been reported yet.
+static NMI_UNREPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether a report of the NMIs has been deferred and not run yet.
+static NMI_REPORT_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// The instruction pointer of the last NMI.
+static LAST_NMI_RIP: AtomicU64 = AtomicU64::new(0);
+
+/// System control port B as read by the last NMI.
+static LAST_NMI_PORT_B: AtomicU8 = AtomicU8::new(0);
+
+/// The `IA32_MCG_CAP` MSR.
+const IA32_MCG_CAP: u32 = 0x179;
+
+/// The `IA32_MCG_STATUS` MSR.
+const IA32_MCG_STATUS: u32 = 0x17a;
+
+/// The `IA32_MC0_STATUS` MSR, the status of bank `i` is at `+ 4 * i`.
+const IA32_MC0_STATUS: u32 = 0x401;
+
+/// Install the handlers of this module.
+///
+/// # Arguments
+/// * `idt` - The IDT to install the handlers in
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    // NMIs and machine checks can arrive at any time, even while the stack
+    // pointer is not valid
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(gdt::NMI_IST_INDEX);
+    }
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// Log the registers saved in the interrupt stack frame and the control
+/// registers.
+///
+/// # Arguments
+/// * `stack_frame` - The stack frame of the interrupt
+pub fn log_register_dump(stack_frame: &InterruptStackFrame) {
+    let cs = stack_frame.code_segment;
+    let ss = stack_frame.stack_segment;
+    log::error!(
+        "  RIP    {}",
+        Symbolized(stack_frame.instruction_pointer.as_u64())
+    );
+    log::error!(
+        "  CS {:#06x} (index {}, {:?})  SS {:#06x} (index {}, {:?})",
+        cs.0,
+        cs.index(),
+        cs.rpl(),
+        ss.0,
+        ss.index(),
+        ss.rpl()
+    );
+    log::error!("  RSP    {:#018x}", stack_frame.stack_pointer.as_u64());
+    log::error!(
+        "  RFLAGS {:#018x}  {:?}",
+        stack_frame.cpu_flags.bits(),
+        stack_frame.cpu_flags
+    );
+    let (cr3, _) = Cr3::read_raw();
+    log::error!(
+        "  CR0 {:#x}  CR2 {:#x}  CR3 {:#x}  CR4 {:#x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        cr3.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+
+/// Report an exception: if it was raised in user mode, log it and return to
+/// the kernel, otherwise log an exception report and panic.
+///
+/// # Arguments
+/// * `name` - The name of the exception
+/// * `stack_frame` - The stack frame of the interrupt
+/// * `details` - A decoded error code or other details, if any
+fn fault(
+    name: &'static str,
+    stack_frame: &InterruptStackFrame,
+    details: Option<fmt::Arguments>,
+) -> ! {
+    if usermode::is_user_mode(stack_frame) {
+        let instruction_pointer = stack_frame.instruction_pointer;
+        log::warn!("user {name} at {:#x}", instruction_pointer.as_u64());
+        if let Some(details) = details {
+            log::warn!("  {details}");
+        }
+        usermode::exit_to_kernel(UserExit::Exception {
+            name,
+            instruction_pointer,
+        });
+    }
+    fatal(name, stack_frame, details)
+}
+
+/// Log an exception report and panic.
+///
+/// # Arguments
+/// * `name` - The name of the exception
+/// * `stack_frame` - The stack frame of the interrupt
+/// * `details` - A decoded error code or other details, if any
+fn fatal(
+    name: &str,
+    stack_frame: &InterruptStackFrame,
+    details: Option<fmt::Arguments>,
+) -> ! {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    log::error!("EXCEPTION: {name} at {rip:#x}");
+    if let Some(details) = details {
+        log::error!("  {details}");
+    }
+    log_register_dump(stack_frame);
+    panic!("EXCEPTION: {name} at {rip:#x}");
+}
+
+/// Describes a selector error code.
+struct Selector(u64);
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = SelectorErrorCode::new_truncate(self.0);
+        if code.is_null() {
+            write!(f, "error code {:#x} (null selector)", self.0)?;
+        } else {
+            match code.descriptor_table() {
+                DescriptorTable::Idt => write!(
+                    f,
+                    "error code {:#x} (IDT vector {})",
+                    self.0,
+                    code.index()
+                )?,
+                table => write!(
+                    f,
+                    "error code {:#x} ({table:?} selector {:#x}, index {})",
+                    self.0,
+                    self.0 & !0b111,
+                    code.index()
+                )?,
+            }
+        }
+        if code.external() {
+            write!(f, ", during delivery of an external event")?;
+        }
+        Ok(())
+    }
+}
+
+/// Divide error (#DE) handler
+extern "x86-interrupt" fn divide_error_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::Division as u8);
+    fault("DIVIDE ERROR", &stack_frame, None);
+}
+
+/// Debug exception (#DB) handler
+///
+/// Logs the debug status register and resumes.
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    stats::record(ExceptionVector::Debug as u8);
+    let dr6: u64;
+    unsafe { asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack)) };
+    log::warn!(
+        "EXCEPTION: DEBUG at {:#x}, DR6 {dr6:#x}",
+        stack_frame.instruction_pointer.as_u64()
+    );
+}
+
+/// Non-maskable interrupt handler
+///
+/// Records the interrupt and resumes. An NMI can arrive while the logger
+/// lock is held and cannot be masked with `cli`, so it must not log; the
+/// report is made by [`defer_nmi_report`] instead.
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    stats::record(ExceptionVector::NonMaskableInterrupt as u8);
+    // bits 6 and 7 of system control port B report parity and channel check
+    // errors on PC compatible systems
+    let port_b = unsafe { Port::<u8>::new(0x61).read() };
+    LAST_NMI_PORT_B.store(port_b, Ordering::Relaxed);
+    LAST_NMI_RIP
+        .store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    NMI_UNREPORTED.fetch_add(1, Ordering::Release);
+}
+
+/// Queue a report of the NMIs recorded since the last report, if any.
+///
+/// Called from the timer interrupt, which unlike the NMI handler can safely
+/// defer work.
+pub fn defer_nmi_report() {
+    if NMI_UNREPORTED.load(Ordering::Relaxed) == 0
+        || NMI_REPORT_QUEUED.swap(true, Ordering::Acquire)
+    {
+        return;
+    }
+    if deferred::defer(report_nmis, 0).is_err() {
+        NMI_REPORT_QUEUED.store(false, Ordering::Release);
+    }
+}
+
+/// Log the NMIs recorded since the last report.
+///
+/// Runs as deferred work (see [`defer_nmi_report`]).
+fn report_nmis(_: u64) {
+    NMI_REPORT_QUEUED.store(false, Ordering::Release);
+    let count = NMI_UNREPORTED.swap(0, Ordering::Acquire);
+    if count == 0 {
+        return;
+    }
+    let rip = LAST_NMI_RIP.load(Ordering::Relaxed);
+    let port_b = LAST_NMI_PORT_B.load(Ordering::Relaxed);
+    log::error!(
+        "NON-MASKABLE INTERRUPT x{count}, last at {rip:#x}, port 0x61 \
+         {port_b:#04x}"
+    );
+}
+
+/// Overflow (#OF) handler
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    stats::record(ExceptionVector::Overflow as u8);
+    fault("OVERFLOW", &stack_frame, None);
+}
+
+/// Bound range exceeded (#BR) handler
+extern "x86-interrupt" fn bound_range_exceeded_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::BoundRange as u8);
+    fault("BOUND RANGE EXCEEDED", &stack_frame, None);
+}
+
+/// Invalid opcode (#UD) handler
+extern "x86-interrupt" fn invalid_opcode_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::InvalidOpcode as u8);
+    fault("INVALID OPCODE", &stack_frame, None);
+}
+
+/// Device not available (#NM) handler
+extern "x86-interrupt" fn device_not_available_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::DeviceNotAvailable as u8);
+    fault(
+        "DEVICE NOT AVAILABLE",
+        &stack_frame,
+        Some(format_args!("CR0 {:?}", Cr0::read())),
+    );
+}
+
+/// Invalid TSS (#TS) handler
+extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::InvalidTss as u8);
+    fault(
+        "INVALID TSS",
+        &stack_frame,
+        Some(format_args!("{}", Selector(error_code))),
+    );
+}
+
+/// Segment not present (#NP) handler
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::SegmentNotPresent as u8);
+    fault(
+        "SEGMENT NOT PRESENT",
+        &stack_frame,
+        Some(format_args!("{}", Selector(error_code))),
+    );
+}
+
+/// Stack segment fault (#SS) handler
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::Stack as u8);
+    fault(
+        "STACK SEGMENT FAULT",
+        &stack_frame,
+        Some(format_args!("{}", Selector(error_code))),
+    );
+}
+
+/// General protection fault (#GP) handler
+///
+/// A zero error code means the fault was not caused by a segment selector,
+/// for example a non-canonical address or a privileged instruction.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::GeneralProtection as u8);
+    if error_code == 0 {
+        fault(
+            "GENERAL PROTECTION FAULT",
+            &stack_frame,
+            Some(format_args!(
+                "error code 0 (not segment related, e.g. a non-canonical \
+                 address or a privileged instruction)"
+            )),
+        );
+    }
+    fault(
+        "GENERAL PROTECTION FAULT",
+        &stack_frame,
+        Some(format_args!("{}", Selector(error_code))),
+    );
+}
+
+/// x87 floating point exception (#MF) handler
+extern "x86-interrupt" fn x87_floating_point_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::X87FloatingPoint as u8);
+    let status: u16;
+    unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack)) };
+    fault(
+        "x87 FLOATING POINT",
+        &stack_frame,
+        Some(format_args!("FPU status word {status:#06x}")),
+    );
+}
+
+/// Alignment check (#AC) handler
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) {
+    stats::record(ExceptionVector::AlignmentCheck as u8);
+    fault("ALIGNMENT CHECK", &stack_frame, None);
+}
+
+/// Machine check (#MC) handler
+///
+/// Logs the global machine check status and every bank holding a valid
+/// error, if the CPU supports the machine check architecture.
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame,
+) -> ! {
+    stats::record(ExceptionVector::MachineCheck as u8);
+    // CPUID.1 EDX bit 14: machine check architecture
+    if unsafe { __cpuid(1) }.edx & (1 << 14) != 0 {
+        let (cap, status) = unsafe {
+            (
+                Msr::new(IA32_MCG_CAP).read(),
+                Msr::new(IA32_MCG_STATUS).read(),
+            )
+        };
+        log::error!("  MCG_STATUS {status:#x}");
+        for bank in 0..(cap & 0xff) as u32 {
+            let bank_status =
+                unsafe { Msr::new(IA32_MC0_STATUS + 4 * bank).read() };
+            // bit 63: the bank holds a valid error
+            if bank_status & (1 << 63) != 0 {
+                log::error!("  bank {bank} status {bank_status:#x}");
+            }
+        }
+    }
+    // a hardware error, even if it was raised in user mode
+    fatal("MACHINE CHECK", &stack_frame, None);
+}
+
+/// SIMD floating point exception (#XM) handler
+extern "x86-interrupt" fn simd_floating_point_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::SimdFloatingPoint as u8);
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack));
+    }
+    fault(
+        "SIMD FLOATING POINT",
+        &stack_frame,
+        Some(format_args!("MXCSR {mxcsr:#x}")),
+    );
+}
+
+/// Virtualization exception (#VE) handler
+extern "x86-interrupt" fn virtualization_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::Virtualization as u8);
+    fault("VIRTUALIZATION", &stack_frame, None);
+}
+
+/// Control protection exception (#CP) handler
+extern "x86-interrupt" fn control_protection_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::ControlProtection as u8);
+    let cause = match error_code & 0x7fff {
+        1 => "near return",
+        2 => "far return or interrupt return",
+        3 => "missing end branch",
+        4 => "shadow stack restore",
+        5 => "shadow stack busy",
+        _ => "unknown",
+    };
+    let enclave = if error_code & (1 << 15) != 0 {
+        ", in an enclave"
+    } else {
+        ""
+    };
+    fault(
+        "CONTROL PROTECTION",
+        &stack_frame,
+        Some(format_args!(
+            "error code {error_code:#x} ({cause}{enclave})"
+        )),
+    );
+}
+
+/// Hypervisor injection exception handler
+extern "x86-interrupt" fn hv_injection_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    stats::record(ExceptionVector::HypervisorInjection as u8);
+    fault("HYPERVISOR INJECTION", &stack_frame, None);
+}
+
+/// VMM communication exception (#VC) handler
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::VmmCommunication as u8);
+    fault(
+        "VMM COMMUNICATION",
+        &stack_frame,
+        Some(format_args!("exit code {error_code:#x}")),
+    );
+}
+
+/// Security exception (#SX) handler
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    stats::record(ExceptionVector::Security as u8);
+    fault(
+        "SECURITY EXCEPTION",
+        &stack_frame,
+        Some(format_args!("error code {error_code:#x}")),
+    );
+}
@@ -1,48 +1,185 @@
 //! Global Descriptor Table (GDT) module.
-use core::ptr::addr_of;
+//!
+//! The GDT holds the kernel and user code and data segments and the TSS. The
+//! user segments follow the kernel data segment in the order `SYSRET`
+//! expects: user data, then user code. Every CPU gets its own GDT and TSS
+//! (see [`init_cpu`]), with the same segment layout.
+extern crate alloc;
 
-use spin::Lazy;
+use alloc::format;
+use core::mem;
+
+use spin::Once;
 use x86_64::{
     registers::segmentation::{SegmentSelector, DS, ES, FS, GS, SS},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
 };
 
+use super::stats::{self, MAX_CPUS};
+use crate::mm::stack::allocate_stack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+
+/// The number of IST slots in a TSS.
+const IST_SLOTS: usize = 7;
+
+/// The maximum number of stacks allocated per CPU: one per IST slot and the
+/// kernel stack.
+pub const MAX_STACKS_PER_CPU: usize = IST_SLOTS + 1;
+
+/// The size of the stack the CPU switches to when an interrupt or exception
+/// arrives in user mode, see [`init_cpu`].
+pub const KERNEL_STACK_SIZE: u64 = 4096 * 16;
+
+/// An interrupt stack in an [`IstLayout`].
+#[derive(Debug, Clone, Copy)]
+pub struct IstStack {
+    /// The name of the stack, used in stack overflow reports.
+    pub name: &'static str,
+    /// The usable size of the stack in bytes, not counting its guard page.
+    pub size: u64,
+}
 
-/// Task State Segment.
+/// The interrupt stacks of a CPU, indexed by IST index.
+///
+/// Exceptions that can arrive while the current stack is unusable get their
+/// own stack: a double fault or page fault may be caused by a stack overflow,
+/// and NMIs and machine checks can interrupt any code, even code that is
+/// switching stacks.
+#[derive(Debug, Clone, Copy)]
+pub struct IstLayout {
+    /// The stack of each IST slot, if any.
+    pub stacks: [Option<IstStack>; IST_SLOTS],
+}
+
+impl IstLayout {
+    /// The layout used unless a CPU is given its own.
+    pub const DEFAULT: Self = IstLayout::empty()
+        .with_stack(DOUBLE_FAULT_IST_INDEX, "double fault stack", 4096 * 5)
+        .with_stack(PAGE_FAULT_IST_INDEX, "page fault stack", 4096 * 5)
+        .with_stack(NMI_IST_INDEX, "NMI stack", 4096 * 4)
+        .with_stack(MACHINE_CHECK_IST_INDEX, "machine check stack", 4096 * 4);
+
+    /// Returns a layout without any interrupt stacks.
+    pub const fn empty() -> Self {
+        IstLayout {
+            stacks: [None; IST_SLOTS],
+        }
+    }
+
+    /// Returns the layout with the stack of IST slot `index` replaced.
+    ///
+    /// # Arguments
+    /// * `index` - The IST index, as used with `set_stack_index`
+    /// * `name` - The name of the stack, used in stack overflow reports
+    /// * `size` - The usable size of the stack in bytes
+    pub const fn with_stack(
+        mut self,
+        index: u16,
+        name: &'static str,
+        size: u64,
+    ) -> Self {
+        self.stacks[index as usize] = Some(IstStack { name, size });
+        self
+    }
+}
+
+/// Task State Segments, indexed by CPU index (see [`stats::cpu_index`]).
 /// Structure on x86-based computers which holds information about a task
-pub static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+static TSS: [Once<TaskStateSegment>; MAX_CPUS] =
+    [const { Once::new() }; MAX_CPUS];
 
-        let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
-        stack_start + STACK_SIZE as u64
-    };
+/// Global Descriptor Tables, indexed by CPU index.
+/// Construct used by the x86 processor to configure segmented virtual memory
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] =
+    [const { Once::new() }; MAX_CPUS];
+
+/// Create a TSS for a CPU, with freshly allocated interrupt stacks.
+///
+/// Besides the interrupt stacks of the layout, the TSS gets a kernel stack
+/// of [`KERNEL_STACK_SIZE`] bytes in `rsp0`, which the CPU switches to when
+/// an interrupt without an interrupt stack arrives in user mode. The stacks
+/// are never freed, as the TSS may be in use for the rest of the
+/// kernel's life.
+///
+/// # Arguments
+/// * `cpu` - The number of the CPU, used in stack overflow reports
+/// * `layout` - The interrupt stacks to allocate
+fn new_tss(cpu: usize, layout: &IstLayout) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    for (index, stack) in layout.stacks.iter().enumerate() {
+        let Some(stack) = stack else {
+            continue;
+        };
+        tss.interrupt_stack_table[index] =
+            interrupt_stack(stack_name(cpu, stack.name), stack.size);
+    }
+    tss.privilege_stack_table[0] =
+        interrupt_stack(stack_name(cpu, "kernel stack"), KERNEL_STACK_SIZE);
     tss
-});
+}
 
-/// Global Descriptor Table.
-/// Construct used by the x86 processor to configure segmented virtual memory
-pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+/// Returns the name of a stack of CPU `cpu`.
+///
+/// Stack overflow reports only name the stack, so the stacks of the
+/// other CPUs are prefixed with their CPU. The name is leaked, like the
+/// stack it names.
+///
+/// # Arguments
+/// * `cpu` - The number of the CPU the stack belongs to
+/// * `name` - The name of the stack in the [`IstLayout`]
+fn stack_name(cpu: usize, name: &'static str) -> &'static str {
+    match cpu {
+        0 => name,
+        cpu => format!("CPU {cpu} {name}").leak(),
+    }
+}
+
+/// Allocate an interrupt stack and return its top.
+///
+/// # Arguments
+/// * `name` - The name of the stack, used in stack overflow reports.
+/// * `size` - The usable size of the stack in bytes.
+fn interrupt_stack(name: &'static str, size: u64) -> VirtAddr {
+    let stack =
+        allocate_stack(size, name).expect("failed to allocate interrupt stack");
+    let top = stack.top();
+    // the TSS references the stack for the rest of the kernel's life
+    mem::forget(stack);
+    top
+}
+
+/// Create a GDT referencing `tss`.
+///
+/// # Arguments
+/// * `tss` - The TSS of the CPU the GDT is for
+fn new_gdt(
+    tss: &'static TaskStateSegment,
+) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.append(Descriptor::kernel_code_segment());
     let data_selector = gdt.append(Descriptor::kernel_data_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
     (
         gdt,
         Selectors {
             code_selector,
             data_selector,
+            user_code_selector: user_selector(user_code_selector),
+            user_data_selector: user_selector(user_data_selector),
             tss_selector,
         },
     )
-});
+}
 
 /// Segment selectors
 pub struct Selectors {
@@ -50,29 +187,92 @@ pub struct Selectors {
     code_selector: SegmentSelector,
     /// Data segment selector
     data_selector: SegmentSelector,
+    /// User code segment selector, with RPL 3
+    user_code_selector: SegmentSelector,
+    /// User data segment selector, with RPL 3
+    user_data_selector: SegmentSelector,
     /// Task State Segment selector
     tss_selector: SegmentSelector,
 }
 
-/// Initialize the GDT
+/// Returns `selector` with its requested privilege level set to ring 3.
+fn user_selector(selector: SegmentSelector) -> SegmentSelector {
+    SegmentSelector::new(selector.index(), PrivilegeLevel::Ring3)
+}
+
+/// Returns the selectors of the calling CPU's GDT.
 ///
-/// Loads the GDT into the CPU
+/// # Panics
+/// Panics if the GDT of the calling CPU has not been initialized.
+fn selectors() -> &'static Selectors {
+    &GDT[stats::cpu_index()]
+        .get()
+        .expect("GDT not initialized on this CPU")
+        .1
+}
+
+/// Returns the kernel code and data segment selectors.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    let selectors = selectors();
+    (selectors.code_selector, selectors.data_selector)
+}
+
+/// Returns the user code and data segment selectors, to be loaded into `CS`
+/// and `SS` when entering user mode.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    let selectors = selectors();
+    (selectors.user_code_selector, selectors.user_data_selector)
+}
+
+/// Returns the TSS of the calling CPU.
+///
+/// # Panics
+/// Panics if the TSS of the calling CPU has not been initialized.
+pub fn tss() -> &'static TaskStateSegment {
+    TSS[stats::cpu_index()]
+        .get()
+        .expect("TSS not initialized on this CPU")
+}
+
+/// Initialize the GDT of the calling CPU with [`IstLayout::DEFAULT`].
 pub fn init() {
+    init_cpu(&IstLayout::DEFAULT);
+}
+
+/// Initialize the GDT and TSS of the calling CPU
+///
+/// Gives the calling CPU its CPU index (see [`stats::register_cpu`]),
+/// creates its TSS with the interrupt stacks of `layout` and a GDT
+/// referencing it, then loads both into the CPU. The interrupt stacks are
+/// allocated with guard pages below them, so the kernel heap and virtual
+/// range allocator must be initialized first.
+///
+/// # Arguments
+/// * `layout` - The interrupt stacks of the CPU
+///
+/// # Panics
+/// Panics if the calling CPU cannot be given a CPU index, e.g. because its
+/// GDT is already initialized.
+pub fn init_cpu(layout: &IstLayout) {
     use x86_64::instructions::{
         segmentation::{Segment, CS},
         tables::load_tss,
     };
 
-    GDT.0.load();
+    let cpu = stats::register_cpu();
+    let tss = TSS[cpu].call_once(|| new_tss(cpu, layout));
+    let (gdt, selectors) = GDT[cpu].call_once(|| new_gdt(tss));
+
+    gdt.load();
 
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(GDT.1.data_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        FS::set_reg(GDT.1.data_selector);
-        GS::set_reg(GDT.1.data_selector);
-
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
+        FS::set_reg(selectors.data_selector);
+        GS::set_reg(selectors.data_selector);
+
+        load_tss(selectors.tss_selector);
     }
 }
@@ -1,12 +1,16 @@
 //! Interrupt Descriptor Table (IDT) module.
 use spin::Lazy;
-use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{structures::idt::InterruptDescriptorTable, PrivilegeLevel};
 
 pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
+    crate::interrupts::exceptions::install(&mut idt);
+
+    // user code may raise breakpoints with int3
     idt.breakpoint
-        .set_handler_fn(crate::interrupts::breakpoint_handler);
+        .set_handler_fn(crate::interrupts::breakpoint_handler)
+        .set_privilege_level(PrivilegeLevel::Ring3);
 
     unsafe {
         idt.double_fault
@@ -14,14 +18,13 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
             .set_stack_index(crate::interrupts::gdt::DOUBLE_FAULT_IST_INDEX);
     }
 
-    idt.page_fault
-        .set_handler_fn(crate::interrupts::page_fault_handler);
-
-    idt[crate::interrupts::InterruptIndex::Timer as u8]
-        .set_handler_fn(crate::devices::timer::timer_handler);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(crate::interrupts::page_fault_handler)
+            .set_stack_index(crate::interrupts::gdt::PAGE_FAULT_IST_INDEX);
+    }
 
-    idt[crate::interrupts::InterruptIndex::Keyboard as u8]
-        .set_handler_fn(crate::devices::keyboard::keyboard_handler);
+    crate::interrupts::irq::install(&mut idt);
 
     idt
 });
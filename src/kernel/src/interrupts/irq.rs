@@ -0,0 +1,310 @@
+//! Hardware interrupt handler registration
+//!
+//! Every vector from [`FIRST_VECTOR`] up to the spurious vector enters the
+//! kernel through a common stub, which calls the handlers registered for the
+//! vector and signals the end of the interrupt to the local APIC. Drivers
+//! allocate a vector with [`allocate_vector`], register any number of
+//! handlers for it with [`register`] and remove them again with
+//! [`unregister`]. Handlers run with interrupts disabled and must not block
+//! or allocate. State they share with other code must be protected by an
+//! [`IrqSafeMutex`](crate::sync::IrqSafeMutex), longer work should be
+//! deferred (see [`crate::task::deferred`]).
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+use spin::RwLock;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use super::stats;
+use crate::{
+    drivers::apic, mm::Locked, sync::InterruptContext, task::deferred,
+};
+
+/// The first vector available for hardware interrupts, vectors below are
+/// reserved for CPU exceptions.
+pub const FIRST_VECTOR: u8 = 0x20;
+
+/// The first vector handed out by [`allocate_vector`]. The vectors below are
+/// left for the fixed assignments in [`super::InterruptIndex`].
+pub const FIRST_DYNAMIC_VECTOR: u8 = 0x30;
+
+/// The vector the local APIC delivers spurious interrupts on. It must not be
+/// acknowledged, so it has its own handler.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The number of vectors handled through the common stubs.
+const VECTOR_COUNT: usize = (SPURIOUS_VECTOR - FIRST_VECTOR) as usize;
+
+/// An interrupt handler.
+pub type HandlerFn = dyn Fn(&InterruptStackFrame) + Send + Sync;
+
+/// A registered handler.
+struct Handler {
+    /// The identifier returned in the [`IrqHandle`].
+    id: u64,
+    /// The name of the handler, for reports.
+    name: &'static str,
+    /// The handler itself.
+    handler: Box<HandlerFn>,
+}
+
+/// The handlers registered for each vector, indexed by
+/// `vector - FIRST_VECTOR`.
+static HANDLERS: [RwLock<Vec<Handler>>; VECTOR_COUNT] =
+    [const { RwLock::new(Vec::new()) }; VECTOR_COUNT];
+
+/// The vectors handed out by [`allocate_vector`], one bit per vector.
+static ALLOCATED: Locked<[u64; 4]> = Locked::new([0; 4]);
+
+/// The identifier of the next registered handler.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The number of interrupts without a handler that have not been reported
+/// yet.
+static UNHANDLED: AtomicU64 = AtomicU64::new(0);
+
+/// The vector of the last interrupt without a handler.
+static LAST_UNHANDLED_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// Whether a report of the unhandled interrupts has been deferred and not
+/// run yet.
+static UNHANDLED_REPORT_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// An error that occurred while registering an interrupt handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector is reserved for CPU exceptions or spurious interrupts.
+    InvalidVector(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::InvalidVector(vector) => {
+                write!(f, "vector {vector:#x} cannot be registered")
+            }
+        }
+    }
+}
+
+/// Identifies a registered handler, pass it to [`unregister`] to remove the
+/// handler again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    /// The vector the handler is registered for.
+    vector: u8,
+    /// The identifier of the handler.
+    id: u64,
+}
+
+impl IrqHandle {
+    /// Returns the vector the handler is registered for.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+/// Returns the handler list of a vector, or `None` if the vector is not
+/// handled through the common stubs.
+fn handlers(vector: u8) -> Option<&'static RwLock<Vec<Handler>>> {
+    let index = vector.checked_sub(FIRST_VECTOR)? as usize;
+    HANDLERS.get(index)
+}
+
+/// Allocate a free vector for a device.
+///
+/// # Returns
+/// The vector, or `None` if every vector from [`FIRST_DYNAMIC_VECTOR`] on is
+/// in use.
+pub fn allocate_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED.lock();
+    let vector = (FIRST_DYNAMIC_VECTOR..SPURIOUS_VECTOR).find(|&vector| {
+        allocated[vector as usize / 64] & (1 << (vector % 64)) == 0
+    })?;
+    allocated[vector as usize / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Return a vector obtained from [`allocate_vector`].
+///
+/// Handlers still registered for the vector stay in place.
+///
+/// # Arguments
+/// * `vector` - The vector to free
+pub fn free_vector(vector: u8) {
+    ALLOCATED.lock()[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
+/// Register a handler for a vector.
+///
+/// Several handlers can share a vector, they are called in the order they
+/// were registered.
+///
+/// # Arguments
+/// * `vector` - The vector to handle
+/// * `name` - The name of the handler, for reports
+/// * `handler` - The function or closure to call when the interrupt arrives
+pub fn register<F>(
+    vector: u8,
+    name: &'static str,
+    handler: F,
+) -> Result<IrqHandle, IrqError>
+where
+    F: Fn(&InterruptStackFrame) + Send + Sync + 'static,
+{
+    let handlers = handlers(vector).ok_or(IrqError::InvalidVector(vector))?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handler = Handler {
+        id,
+        name,
+        handler: Box::new(handler),
+    };
+    // the stub of this vector takes the read lock, so it must not interrupt
+    // us while we hold the write lock
+    without_interrupts(|| handlers.write().push(handler));
+    Ok(IrqHandle { vector, id })
+}
+
+/// Remove a handler registered with [`register`].
+///
+/// # Arguments
+/// * `handle` - The handle returned by [`register`]
+///
+/// # Returns
+/// `true` if the handler was registered.
+pub fn unregister(handle: IrqHandle) -> bool {
+    let Some(handlers) = handlers(handle.vector) else {
+        return false;
+    };
+    let removed = without_interrupts(|| {
+        let mut handlers = handlers.write();
+        let index = handlers.iter().position(|h| h.id == handle.id)?;
+        Some(handlers.remove(index))
+    });
+    // the handler is dropped here, outside of the lock
+    removed.is_some()
+}
+
+/// Call the handlers of a vector and acknowledge the interrupt.
+///
+/// Does not log, the interrupted code may hold the logger lock. Interrupts
+/// without a handler are reported from deferred work instead.
+///
+/// # Arguments
+/// * `vector` - The vector of the interrupt
+/// * `stack_frame` - The stack frame of the interrupt
+fn dispatch(vector: u8, stack_frame: &InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    stats::record(vector);
+    if let Some(handlers) = handlers(vector) {
+        let handlers = handlers.read();
+        if handlers.is_empty() {
+            LAST_UNHANDLED_VECTOR.store(vector, Ordering::Relaxed);
+            UNHANDLED.fetch_add(1, Ordering::Release);
+            defer_unhandled_report();
+        }
+        for handler in handlers.iter() {
+            (handler.handler)(stack_frame);
+        }
+    }
+    apic::end_interrupt();
+}
+
+/// Queue a report of the unhandled interrupts, unless one is queued already.
+fn defer_unhandled_report() {
+    if UNHANDLED_REPORT_QUEUED.swap(true, Ordering::Acquire) {
+        return;
+    }
+    if deferred::defer(report_unhandled, 0).is_err() {
+        UNHANDLED_REPORT_QUEUED.store(false, Ordering::Release);
+    }
+}
+
+/// Log the interrupts without a handler since the last report.
+///
+/// Runs as deferred work (see [`defer_unhandled_report`]).
+fn report_unhandled(_: u64) {
+    UNHANDLED_REPORT_QUEUED.store(false, Ordering::Release);
+    let count = UNHANDLED.swap(0, Ordering::Acquire);
+    if count == 0 {
+        return;
+    }
+    let vector = LAST_UNHANDLED_VECTOR.load(Ordering::Relaxed);
+    log::warn!("{count} unhandled interrupts, last on vector {vector:#x}");
+}
+
+/// Returns the names of the handlers registered for a vector.
+///
+/// # Arguments
+/// * `vector` - The vector to look up
+pub fn handler_names(vector: u8) -> Vec<&'static str> {
+    let Some(handlers) = handlers(vector) else {
+        return Vec::new();
+    };
+    without_interrupts(|| {
+        handlers.read().iter().map(|handler| handler.name).collect()
+    })
+}
+
+/// Log the registered handlers of every vector.
+///
+/// # Arguments
+/// * `level` - The log level to use
+pub fn log_handlers(level: log::Level) {
+    for vector in FIRST_VECTOR..SPURIOUS_VECTOR {
+        let handlers = handler_names(vector);
+        if !handlers.is_empty() {
+            log::log!(level, "  {vector:#04x}: {}", handlers.join(", "));
+        }
+    }
+}
+
+/// The common stub of a vector.
+extern "x86-interrupt" fn irq_stub<const VECTOR: u8>(
+    stack_frame: InterruptStackFrame,
+) {
+    dispatch(VECTOR, &stack_frame);
+}
+
+/// Spurious interrupt handler
+///
+/// Spurious interrupts are only counted, not acknowledged.
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    stats::record(SPURIOUS_VECTOR);
+}
+
+/// Installs [`irq_stub`] for the vectors `0xHL` with the given high nibbles
+/// `H` and every low nibble `L`.
+macro_rules! install_stubs {
+    ($idt:ident; $($high:literal),*) => {
+        $(install_stubs!(@row $idt, $high);)*
+    };
+    (@row $idt:ident, $high:literal) => {
+        install_stubs!(@cells $idt, $high;
+            0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7,
+            0x8, 0x9, 0xa, 0xb, 0xc, 0xd, 0xe, 0xf);
+    };
+    (@cells $idt:ident, $high:literal; $($low:literal),*) => {
+        $($idt[$high << 4 | $low]
+            .set_handler_fn(irq_stub::<{ $high << 4 | $low }>);)*
+    };
+}
+
+/// Install the common stubs and the spurious interrupt handler.
+///
+/// # Arguments
+/// * `idt` - The IDT to install the stubs in
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    install_stubs!(idt; 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9,
+        0xa, 0xb, 0xc, 0xd, 0xe, 0xf);
+    // the last stub installed above is overridden
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
+}
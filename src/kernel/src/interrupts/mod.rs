@@ -1,14 +1,31 @@
 //! Interrupt handling module
+pub mod exceptions;
 pub mod gdt;
 pub mod idt;
+pub mod irq;
+pub mod stats;
 
 use x86_64::{
     registers::control::Cr2,
-    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{
+        ExceptionVector, InterruptStackFrame, PageFaultErrorCode,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    mm::{
+        demand_paging,
+        guard::{self, GuardKind},
+        paging,
+    },
+    usermode::{self, UserExit},
 };
 
 pub const PIC_1_OFFSET: u8 = 0x20;
 
+/// Vectors with a fixed assignment. Their handlers are registered through
+/// [`irq`] like any other.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -19,18 +36,32 @@ pub enum InterruptIndex {
 /// Breakpoint exception handler
 ///
 /// This function is called when a breakpoint exception occurs, panics and
-/// prints the stack frame
+/// prints the stack frame. Breakpoints in user mode return to the kernel
+/// instead
 ///
 /// # Arguments
 /// * `stack_frame` - The stack frame of the interrupt
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    stats::record(ExceptionVector::Breakpoint as u8);
+    if usermode::is_user_mode(&stack_frame) {
+        log::warn!(
+            "user breakpoint at {:#x}",
+            stack_frame.instruction_pointer.as_u64()
+        );
+        usermode::exit_to_kernel(UserExit::Exception {
+            name: "BREAKPOINT",
+            instruction_pointer: stack_frame.instruction_pointer,
+        });
+    }
     panic!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 /// Double fault exception handler
 ///
 /// This function is called when a double fault exception occurs, panics and
-/// prints the stack frame
+/// prints the stack frame. If the interrupted stack pointer or the last page
+/// fault address lies in a stack guard page, the fault is reported as a stack
+/// overflow
 ///
 /// # Arguments
 /// * `stack_frame` - The stack frame of the interrupt
@@ -38,13 +69,34 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    stats::record(ExceptionVector::Double as u8);
+    let fault_addr = VirtAddr::new_truncate(Cr2::read_raw());
+    let overflow = [stack_frame.stack_pointer, fault_addr]
+        .into_iter()
+        .filter_map(guard::find)
+        .find(|guard| guard.kind == GuardKind::StackOverflow);
+    if let Some(guard) = overflow {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT caused by {}\n{:#?}",
+            guard, stack_frame
+        );
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/// How far [`page_fault_handler`] searches for neighbouring mappings of the
+/// faulting address.
+const NEAREST_MAPPING_DISTANCE: u64 = 1 << 30; // 1 GiB
+
 /// Page fault exception handler
 ///
-/// This function is called when a page fault exception occurs, logs the
-/// accessed address, error code and the stack frame
+/// This function is called when a page fault exception occurs. Faults in
+/// demand paged regions are resolved by mapping a fresh page and other
+/// faults in user mode return to the kernel. Every other fault logs the decoded
+/// error code, the page table walk for the accessed address and the nearest
+/// mappings around it, then panics. Faults on a guard page are reported as a
+/// stack overflow or heap overrun. The handler runs on its own interrupt stack
+/// so that it still works when the faulting code has run out of stack
 ///
 /// # Arguments
 /// * `stack_frame` - The stack frame of the interrupt
@@ -53,8 +105,114 @@ pub extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
-    log::info!("Exception : Page Fault");
-    log::info!("Accessed address : {:?}", Cr2::read());
-    log::info!("ErrorCode : {:?}", error_code);
-    log::info!("{:#?}", stack_frame);
+    stats::record(ExceptionVector::Page as u8);
+    let addr = VirtAddr::new_truncate(Cr2::read_raw());
+    if demand_paging::handle_page_fault(addr, error_code) {
+        return;
+    }
+    if usermode::is_user_mode(&stack_frame) {
+        log::warn!(
+            "user page fault at {:#x} accessing {:#x}",
+            stack_frame.instruction_pointer.as_u64(),
+            addr.as_u64()
+        );
+        log_page_fault_cause(error_code);
+        usermode::exit_to_kernel(UserExit::PageFault {
+            address: addr,
+            error_code,
+            instruction_pointer: stack_frame.instruction_pointer,
+        });
+    }
+
+    let guard = guard::find(addr);
+
+    log::error!("EXCEPTION: PAGE FAULT");
+    log::error!("Accessed address : {:#x}", addr.as_u64());
+    log::error!("ErrorCode : {error_code:?}");
+    log_page_fault_cause(error_code);
+    if let Some(guard) = guard {
+        log::error!("  hit guard page: {guard}");
+    }
+    if let Some(region) = demand_paging::find(addr) {
+        log::error!("  in demand paged region {:?}", region.name);
+    }
+    log::error!("{}", paging::walk(addr));
+
+    let (below, above) =
+        paging::nearest_mappings(addr, NEAREST_MAPPING_DISTANCE);
+    match below {
+        Some(page) => log::error!(
+            "Nearest mapping below : {:#x} ({:#x} bytes away)",
+            page.as_u64(),
+            addr - page
+        ),
+        None => log::error!("Nearest mapping below : none"),
+    }
+    match above {
+        Some(page) => log::error!(
+            "Nearest mapping above : {:#x} ({:#x} bytes away)",
+            page.as_u64(),
+            page - addr
+        ),
+        None => log::error!("Nearest mapping above : none"),
+    }
+
+    match guard {
+        Some(guard) if guard.kind == GuardKind::StackOverflow => panic!(
+            "EXCEPTION: STACK OVERFLOW in {} at {:#x}\n{:#?}",
+            guard.name,
+            addr.as_u64(),
+            stack_frame
+        ),
+        Some(guard) => panic!(
+            "EXCEPTION: PAGE FAULT ({}) at {:#x}\n{:#?}",
+            guard,
+            addr.as_u64(),
+            stack_frame
+        ),
+        None => panic!(
+            "EXCEPTION: PAGE FAULT at {:#x}\n{:#?}",
+            addr.as_u64(),
+            stack_frame
+        ),
+    }
+}
+
+/// Log the meaning of each bit of a page fault error code.
+///
+/// # Arguments
+/// * `error_code` - The error code of the page fault
+fn log_page_fault_cause(error_code: PageFaultErrorCode) {
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        "protection violation on a present page"
+    } else {
+        "page not present"
+    };
+    log::error!("  {mode} {access} access, {cause}");
+
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        log::error!("  reserved bit set in a page table entry");
+    }
+    if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+        log::error!("  protection key violation");
+    }
+    if error_code.contains(PageFaultErrorCode::SHADOW_STACK) {
+        log::error!("  shadow stack access");
+    }
+    if error_code.contains(PageFaultErrorCode::SGX) {
+        log::error!("  SGX access control violation");
+    }
 }
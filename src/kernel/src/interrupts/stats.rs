@@ -0,0 +1,228 @@
+//! Interrupt statistics
+//!
+//! Counts every interrupt and exception per vector and per CPU. The counters
+//! are updated by the exception handlers and the common interrupt stubs (see
+//! [`super::irq`]) and can be read as a [`snapshot`] or logged with
+//! [`log_stats`].
+extern crate alloc;
+
+use alloc::{format, string::String, vec::Vec};
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+
+use super::irq;
+use crate::drivers::apic::local_apic;
+
+/// The number of CPUs counted separately, and the number of CPUs that can
+/// be given a CPU index (see [`register_cpu`]). CPUs with a higher local
+/// APIC ID share the last counters.
+pub const MAX_CPUS: usize = 16;
+
+/// The number of interrupt vectors.
+const VECTOR_COUNT: usize = 256;
+
+/// The number of local APIC IDs that can be given a CPU index.
+const MAX_APIC_ID: usize = 256;
+
+/// Marks a local APIC ID that has no CPU index.
+const NO_CPU_INDEX: u8 = u8::MAX;
+
+/// The CPU index of each local APIC ID, see [`register_cpu`].
+static CPU_INDICES: [AtomicU8; MAX_APIC_ID] =
+    [const { AtomicU8::new(NO_CPU_INDEX) }; MAX_APIC_ID];
+
+/// The number of CPUs given an index.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The counters, indexed by CPU and vector.
+static COUNTS: [[AtomicU64; VECTOR_COUNT]; MAX_CPUS] =
+    [const { [const { AtomicU64::new(0) }; VECTOR_COUNT] }; MAX_CPUS];
+
+/// The names of the CPU exceptions, indexed by vector.
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "NMI",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point",
+    "alignment check",
+    "machine check",
+    "SIMD floating point",
+    "virtualization",
+    "control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection",
+    "VMM communication",
+    "security exception",
+    "reserved",
+];
+
+/// Returns the number of the calling CPU, its local APIC ID.
+///
+/// Reads the local APIC if it is not locked, and falls back to the initial
+/// APIC ID reported by CPUID, which is slower under virtualization.
+pub fn current_cpu() -> usize {
+    local_apic::id().unwrap_or_else(|| unsafe { __cpuid(1) }.ebx >> 24) as usize
+}
+
+/// Give the calling CPU the next free CPU index.
+///
+/// CPU indices are dense, unlike local APIC IDs, and index the per-CPU
+/// tables such as the GDTs. Called once per CPU by
+/// [`super::gdt::init_cpu`].
+///
+/// # Returns
+/// The index of the calling CPU.
+///
+/// # Panics
+/// Panics if the local APIC ID of the calling CPU is too large, if more than
+/// [`MAX_CPUS`] CPUs are registered or if the calling CPU already has an
+/// index.
+pub fn register_cpu() -> usize {
+    let apic_id = current_cpu();
+    assert!(
+        apic_id < MAX_APIC_ID,
+        "local APIC ID {apic_id} out of range"
+    );
+    let index = CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+    assert!(index < MAX_CPUS, "more than {MAX_CPUS} CPUs");
+    assert!(
+        CPU_INDICES[apic_id]
+            .compare_exchange(
+                NO_CPU_INDEX,
+                index as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire
+            )
+            .is_ok(),
+        "CPU with local APIC ID {apic_id} registered twice"
+    );
+    index
+}
+
+/// Returns the index of the calling CPU, see [`register_cpu`].
+///
+/// Takes no locks, so it can be called from any context.
+///
+/// # Panics
+/// Panics if the calling CPU has not been given an index.
+pub fn cpu_index() -> usize {
+    let apic_id = current_cpu();
+    let index = CPU_INDICES
+        .get(apic_id)
+        .map_or(NO_CPU_INDEX, |index| index.load(Ordering::Acquire));
+    assert!(
+        index != NO_CPU_INDEX,
+        "CPU with local APIC ID {apic_id} has no CPU index"
+    );
+    index as usize
+}
+
+/// Count an interrupt on the calling CPU.
+///
+/// # Arguments
+/// * `vector` - The vector of the interrupt
+pub fn record(vector: u8) {
+    let cpu = current_cpu().min(MAX_CPUS - 1);
+    COUNTS[cpu][vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// The counters of a vector.
+#[derive(Debug, Clone, Copy)]
+pub struct VectorStats {
+    /// The vector.
+    pub vector: u8,
+    /// The number of interrupts, indexed by CPU.
+    pub per_cpu: [u64; MAX_CPUS],
+}
+
+impl VectorStats {
+    /// Returns the number of interrupts on all CPUs.
+    pub fn total(&self) -> u64 {
+        self.per_cpu.iter().sum()
+    }
+}
+
+/// Returns the counters of a vector.
+///
+/// # Arguments
+/// * `vector` - The vector to read
+pub fn vector_stats(vector: u8) -> VectorStats {
+    VectorStats {
+        vector,
+        per_cpu: core::array::from_fn(|cpu| {
+            COUNTS[cpu][vector as usize].load(Ordering::Relaxed)
+        }),
+    }
+}
+
+/// Returns the counters of every vector that has seen an interrupt.
+///
+/// The counters keep changing while they are read, so the snapshot is not
+/// consistent across vectors.
+pub fn snapshot() -> Vec<VectorStats> {
+    (0..=u8::MAX)
+        .map(vector_stats)
+        .filter(|stats| stats.total() != 0)
+        .collect()
+}
+
+/// Describes the source of a vector: the exception or the registered
+/// handlers.
+fn describe(vector: u8) -> String {
+    match vector {
+        0..32 => EXCEPTION_NAMES[vector as usize].into(),
+        irq::SPURIOUS_VECTOR => "spurious".into(),
+        vector => {
+            let names = irq::handler_names(vector);
+            if names.is_empty() {
+                "unhandled".into()
+            } else {
+                names.join(", ")
+            }
+        }
+    }
+}
+
+/// Log the counters of every vector that has seen an interrupt.
+///
+/// # Arguments
+/// * `level` - The log level to use
+pub fn log_stats(level: log::Level) {
+    let snapshot = snapshot();
+    let cpus = snapshot
+        .iter()
+        .flat_map(|stats| stats.per_cpu.iter().rposition(|&count| count != 0))
+        .max()
+        .map_or(1, |cpu| cpu + 1);
+
+    log::log!(level, "Interrupts per vector and CPU:");
+    for stats in snapshot {
+        let mut line = format!("  {:#04x} {:>10}", stats.vector, stats.total());
+        if cpus > 1 {
+            for count in &stats.per_cpu[..cpus] {
+                line.push_str(&format!(" {count:>10}"));
+            }
+        }
+        log::log!(level, "{line}  {}", describe(stats.vector));
+    }
+}
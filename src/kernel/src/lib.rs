@@ -4,16 +4,20 @@
 use bootloader_api::BootInfo;
 use x86_64::{instructions, VirtAddr};
 
+pub mod debug;
 pub mod devices;
 pub mod drivers;
 pub mod graphics;
 pub mod interrupts;
 pub mod logger;
 pub mod mm;
+pub mod sync;
 pub mod task;
+pub mod usermode;
 
-/// Initializes the kernel by setting up the logger, GDT, IDT, enabling
-/// interrupts, initializing the heap, and initializing the drivers.
+/// Initializes the kernel by setting up the logger, initializing the heap,
+/// setting up the GDT and IDT, enabling interrupts, initializing the drivers,
+/// and reclaiming the memory used by the bootloader.
 ///
 /// # Arguments
 /// * `framework_info` - The [`BootInfo`] struct that contains the information
@@ -33,6 +37,8 @@ pub fn init(
     let framebuffer = framework_info.framebuffer.take().unwrap();
     let info = framebuffer.info();
     let buffer = framebuffer.into_buffer();
+    let framebuffer_start = VirtAddr::from_ptr(buffer.as_ptr());
+    let framebuffer_size = buffer.len();
     logger::init(
         info,
         buffer,
@@ -40,11 +46,6 @@ pub fn init(
         serial_logger_status,
     );
 
-    // initialize GDT, IDT, and enable interrupts
-    interrupts::gdt::init();
-    interrupts::idt::init();
-    x86_64::instructions::interrupts::enable();
-
     // initialize heap and memory allocator
     let physical_memory_offset = VirtAddr::new(
         framework_info
@@ -52,11 +53,47 @@ pub fn init(
             .take()
             .expect("Failed to find physical memory offset"),
     );
-    let mut mapper = mm::paging::init(physical_memory_offset);
-    let mut allocator =
-        mm::paging::BootInfoFrameAllocator::new(&framework_info.memory_regions);
-    mm::allocator::init_heap(&mut mapper, &mut allocator)
-        .expect("heap initialization failed");
+    mm::boot_memory::log_memory_map(
+        &framework_info.memory_regions,
+        debug::console::OUTPUT_LEVEL,
+    );
+    mm::paging::init(physical_memory_offset);
+    mm::pat::init();
+    mm::stack::register_boot_stack_guard();
+    {
+        let mut allocator = mm::frame_allocator::FRAME_ALLOCATOR.lock();
+        unsafe {
+            allocator
+                .init(&framework_info.memory_regions, physical_memory_offset);
+        }
+        log::info!(
+            "Physical memory: {} of {} frames free",
+            allocator.free_frames(),
+            allocator.usable_frames()
+        );
+    }
+    mm::allocator::init_heap().expect("heap initialization failed");
+    mm::vmm::init();
+
+    // pixels are only ever written, so let the CPU combine the writes
+    if mm::pat::is_enabled() {
+        unsafe {
+            mm::mmio::set_cache_policy(
+                framebuffer_start,
+                framebuffer_size,
+                mm::mmio::CachePolicy::WriteCombining,
+            )
+        }
+        .expect("failed to map the framebuffer as write-combining");
+    }
+
+    // initialize GDT, IDT, and enable interrupts. The interrupt stacks are
+    // allocated from the kernel virtual region, so this comes after the
+    // memory setup
+    interrupts::gdt::init();
+    interrupts::idt::init();
+    usermode::syscall::init();
+    x86_64::instructions::interrupts::enable();
 
     // initialize drivers
     let rsdp_addr = framework_info
@@ -64,13 +101,14 @@ pub fn init(
         .take()
         .expect("Failed to find RSDP address");
     unsafe {
-        drivers::init(
-            rsdp_addr as usize,
-            physical_memory_offset,
-            &mut mapper,
-            &mut allocator,
-        );
+        drivers::init(rsdp_addr as usize, physical_memory_offset);
     }
+
+    // hand the memory only the bootloader needed to the frame allocator
+    let reclaimed = mm::boot_memory::reclaim_bootloader_memory(
+        &framework_info.memory_regions,
+    );
+    log::info!("Reclaimed {} KiB of bootloader memory", reclaimed * 4);
 }
 
 /// Halts the CPU by triggering the [`x86_64::instructions::hlt`] instruction in
@@ -3,20 +3,29 @@
 
 extern crate alloc;
 
-use core::panic::PanicInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use bootloader_api::{
     config::{BootloaderConfig, Mapping},
     entry_point, BootInfo,
 };
 use kernel::{
-    devices::keyboard,
-    task::{executor, Task},
+    debug::console,
+    task::{deferred, executor, Task},
 };
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // keep everything the bootloader maps in the kernel half, so that it is
+    // shared by every address space
+    config.mappings.dynamic_range_start =
+        Some(kernel::mm::paging::BOOTLOADER_DYNAMIC_START);
+    config.mappings.dynamic_range_end =
+        Some(kernel::mm::paging::BOOTLOADER_DYNAMIC_END);
     config
 };
 
@@ -33,7 +42,9 @@ fn kernel_main(info: &'static mut BootInfo) -> ! {
 
     let mut executor = executor::Executor::new();
 
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn_high_priority(Task::new(deferred::run()));
+
+    executor.spawn(Task::new(console::run()));
 
     // executor.spawn(Task::new(async move {
     //     kernel::graphics::examples::tga::draw_tga(
@@ -59,15 +70,22 @@ fn kernel_main(info: &'static mut BootInfo) -> ! {
     executor.run();
 }
 
+/// Whether a panic is being handled, to detect panics while printing one.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 /// Simple panic handler that loops forever
 ///
 /// # Arguments
 /// * `info` - The panic information
 ///
-/// Logs the panic information and then enters an infinite loop using
-/// [`kernel::hlt_loop`] to prevent the kernel from crashing.
+/// Logs the panic information and a backtrace and then enters an infinite
+/// loop using [`kernel::hlt_loop`] to prevent the kernel from crashing. A
+/// panic while printing the backtrace only logs its message.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log::error!("[PANIC]: {}", info);
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        kernel::debug::backtrace::log_backtrace(log::Level::Error);
+    }
     kernel::hlt_loop();
 }
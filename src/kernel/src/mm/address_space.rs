@@ -0,0 +1,266 @@
+//! Per-process address spaces
+//!
+//! An [`AddressSpace`] owns its own level 4 table. The lower half holds the
+//! process' user pages, the upper half is copied from the kernel's table and
+//! therefore shares the kernel's level 3 tables, so kernel mappings made after
+//! the copy are visible in every address space.
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging};
+
+/// Marks leaf entries whose frame was allocated by the address space and is
+/// freed with it. Uses one of the bits available to the OS.
+const OWNED_FRAME: PageTableFlags = PageTableFlags::BIT_9;
+
+/// An isolated address space with a shared kernel half.
+///
+/// Dropping the address space frees its page tables and all frames mapped
+/// with [`AddressSpace::map_user_page`]. If it is still active, the kernel's
+/// page table is activated first.
+pub struct AddressSpace {
+    /// The frame of the level 4 table.
+    level_4_frame: PhysFrame,
+    /// The page table of the address space.
+    mapper: OffsetPageTable<'static>,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty lower half.
+    ///
+    /// The upper half is copied from the kernel's page table.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4_frame = FRAME_ALLOCATOR
+            .lock()
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let level_4_table = unsafe { table_at(level_4_frame.start_address()) };
+        level_4_table.zero();
+
+        {
+            let kernel_mapper = paging::mapper();
+            let kernel_table = kernel_mapper.level_4_table();
+            for index in paging::KERNEL_HALF_FIRST_ENTRY..512 {
+                level_4_table[index] = kernel_table[index].clone();
+            }
+        }
+
+        let mapper = unsafe {
+            OffsetPageTable::new(
+                level_4_table,
+                paging::physical_memory_offset(),
+            )
+        };
+        Ok(AddressSpace {
+            level_4_frame,
+            mapper,
+        })
+    }
+
+    /// Returns the frame of the level 4 table.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns `true` if the address space is the one currently in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switch to the address space by loading its level 4 table into CR3.
+    ///
+    /// # Safety
+    /// The caller must ensure that nothing running after the switch relies on
+    /// lower half mappings of the previous address space.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.level_4_frame, Cr3Flags::empty());
+        }
+    }
+
+    /// Map a fresh zeroed frame at `page` in the lower half.
+    ///
+    /// # Arguments
+    /// * `page` - The user page to map.
+    /// * `flags` - Additional flags, e.g. `WRITABLE` or `NO_EXECUTE`. `PRESENT`
+    ///   and `USER_ACCESSIBLE` are always set.
+    ///
+    /// # Returns
+    /// The frame the page was mapped to.
+    ///
+    /// # Panics
+    /// Panics if `page` is in the kernel half.
+    pub fn map_user_page(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        assert_user_page(page);
+
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            let frame_ptr: *mut u8 = (paging::physical_memory_offset()
+                + frame.start_address().as_u64())
+            .as_mut_ptr();
+            frame_ptr.write_bytes(0, Size4KiB::SIZE as usize);
+        }
+
+        let flags = flags
+            | PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | OWNED_FRAME;
+        let result = unsafe {
+            self.mapper
+                .map_to(page, frame, flags, &mut *frame_allocator)
+        };
+        match result {
+            // not flushed, the address space may not be active. Mapping a new
+            // page never leaves a stale TLB entry behind
+            Ok(flush) => flush.ignore(),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(error);
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Map fresh zeroed frames for every page of a range in the lower half.
+    ///
+    /// # Arguments
+    /// * `start` - The first address of the range.
+    /// * `size` - The size of the range in bytes.
+    /// * `flags` - Additional flags, see [`Self::map_user_page`].
+    ///
+    /// # Returns
+    /// On failure, the pages mapped so far stay mapped and are freed with
+    /// the address space.
+    pub fn map_user_range(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        if size == 0 {
+            return Ok(());
+        }
+        let first = Page::containing_address(start);
+        let last = Page::containing_address(start + (size - 1));
+        for page in Page::range_inclusive(first, last) {
+            self.map_user_page(page, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Unmap a page mapped with [`Self::map_user_page`] and free its frame.
+    ///
+    /// # Panics
+    /// Panics if `page` is in the kernel half.
+    pub fn unmap_user_page(
+        &mut self,
+        page: Page<Size4KiB>,
+    ) -> Result<(), UnmapError> {
+        assert_user_page(page);
+
+        let flags = match self.mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => return Err(UnmapError::PageNotMapped),
+        };
+        let (frame, flush) = self.mapper.unmap(page)?;
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        if flags.contains(OWNED_FRAME) {
+            unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// Returns the physical address `addr` is mapped to in this address
+    /// space.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe {
+                Cr3::write(paging::kernel_level_4_frame(), Cr3Flags::empty())
+            };
+        }
+
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let level_4_table = self.mapper.level_4_table();
+        for entry in level_4_table.iter().take(paging::KERNEL_HALF_FIRST_ENTRY)
+        {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                unsafe { free_table(entry.addr(), 3, &mut *frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Free a lower half page table, its child tables and all owned frames
+/// mapped through it.
+///
+/// # Safety
+/// The table must belong to a single address space and not be used anymore.
+///
+/// # Arguments
+/// * `table_addr` - The physical address of the table.
+/// * `level` - The level of the table, from 3 down to 1.
+/// * `frame_allocator` - The allocator to return the frames to.
+unsafe fn free_table(
+    table_addr: PhysAddr,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = table_at(table_addr);
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            free_table(entry.addr(), level - 1, frame_allocator);
+        } else if level == 1 && flags.contains(OWNED_FRAME) {
+            frame_allocator
+                .deallocate_frame(PhysFrame::containing_address(entry.addr()));
+        }
+    }
+    frame_allocator.deallocate_frame(PhysFrame::containing_address(table_addr));
+}
+
+/// Returns the page table stored in the frame at `addr`.
+///
+/// # Safety
+/// The frame must hold a page table (or be about to be initialized as one)
+/// that is not otherwise referenced mutably.
+unsafe fn table_at(addr: PhysAddr) -> &'static mut PageTable {
+    let virt = paging::physical_memory_offset() + addr.as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Panic if `page` does not lie in the lower half.
+fn assert_user_page(page: Page<Size4KiB>) {
+    assert!(
+        usize::from(page.p4_index()) < paging::KERNEL_HALF_FIRST_ENTRY,
+        "{:#x} is not a user address",
+        page.start_address().as_u64()
+    );
+}
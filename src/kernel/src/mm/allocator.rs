@@ -7,17 +7,32 @@ use core::{
 extern crate alloc;
 
 use x86_64::{
+    align_up,
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags,
-        Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page,
+        PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
-use super::Locked;
+use super::{
+    frame_allocator::FRAME_ALLOCATOR,
+    guard::{self, GuardKind},
+    paging, Locked,
+};
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// The start of the kernel heap, in the kernel half above the bootloader's
+/// mappings so that it is shared by every address space.
+pub const HEAP_START: usize = 0x_ffff_c444_4444_0000;
+/// The size of the heap mapped by [`init_heap`].
+pub const HEAP_INITIAL_SIZE: usize = 100 * 1024; // 100 KiB
+/// The largest size the heap may ever grow to, see [`set_heap_limit`].
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The size of the unmapped sentinels directly below [`HEAP_START`] and
+/// directly above the largest possible heap.
+pub const HEAP_GUARD_SIZE: usize = 4096;
+/// The minimum number of bytes the heap grows by at once.
+const HEAP_GROWTH: usize = 64 * 1024; // 64 KiB
 
 /// The block sizes to use.
 ///
@@ -33,24 +48,180 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// The minimum size of a slab.
+const MIN_SLAB_SIZE: usize = 4096;
+
+/// The minimum number of blocks a slab is carved into, including the blocks
+/// occupied by its header.
+const MIN_BLOCKS_PER_SLAB: usize = 8;
+
+/// The number of completely free slabs each size class keeps around instead
+/// of returning them to the fallback heap right away.
+const EMPTY_SLABS_KEPT: usize = 1;
+
+/// Returns the size of the slabs used for the size class `index`.
+///
+/// Slabs are aligned to their size, so the slab owning a block can be found by
+/// rounding the block's address down.
+const fn slab_size(index: usize) -> usize {
+    let size = BLOCK_SIZES[index] * MIN_BLOCKS_PER_SLAB;
+    if size > MIN_SLAB_SIZE {
+        size
+    } else {
+        MIN_SLAB_SIZE
+    }
+}
+
+/// Returns the number of blocks at the start of a slab of the size class
+/// `index` that are occupied by the slab header.
+const fn header_blocks(index: usize) -> usize {
+    mem::size_of::<Slab>().div_ceil(BLOCK_SIZES[index])
+}
+
+/// Returns the number of usable blocks in a slab of the size class `index`.
+const fn blocks_per_slab(index: usize) -> usize {
+    slab_size(index) / BLOCK_SIZES[index] - header_blocks(index)
+}
+
 /// A node in a singly-linked list.
 struct ListNode {
     /// The next node in the list.
     next: Option<&'static mut ListNode>,
 }
 
+/// The header at the start of every slab.
+///
+/// A slab is a block of memory taken from the fallback heap and carved into
+/// blocks of a single size class. Slabs with free blocks are kept in a
+/// doubly-linked list per size class, full slabs are not linked at all.
+struct Slab {
+    /// The free blocks of this slab.
+    free_list: Option<&'static mut ListNode>,
+    /// The number of blocks handed out from this slab.
+    used: usize,
+    /// The previous slab with free blocks of the same size class.
+    prev: *mut Slab,
+    /// The next slab with free blocks of the same size class.
+    next: *mut Slab,
+}
+
+/// A block size class.
+struct SizeClass {
+    /// The first slab of this class that has free blocks.
+    partial: *mut Slab,
+    /// The number of slabs owned by this class.
+    slabs: usize,
+    /// The number of slabs of this class without any used blocks.
+    empty_slabs: usize,
+}
+
+/// Counters for a single block size class.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    /// The block size of the class in bytes.
+    pub block_size: usize,
+    /// The number of blocks handed out from this class.
+    pub allocations: u64,
+    /// The number of blocks returned to this class.
+    pub frees: u64,
+    /// The number of free blocks cached in the class's slabs.
+    pub cached_blocks: usize,
+    /// The number of slabs owned by the class.
+    pub slabs: usize,
+    /// The number of slabs of the class without any used blocks.
+    pub empty_slabs: usize,
+    /// The number of slabs the class returned to the fallback heap.
+    pub released_slabs: u64,
+}
+
+/// A snapshot of the global allocator's statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Counters for each block size class.
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    /// The number of allocations too large for any size class.
+    pub large_allocations: u64,
+    /// The number of frees of allocations too large for any size class.
+    pub large_frees: u64,
+    /// The current size of the fallback heap in bytes.
+    pub heap_size: usize,
+    /// The number of bytes in use in the fallback heap.
+    pub heap_used: usize,
+    /// The highest number of bytes ever in use in the fallback heap.
+    pub heap_high_water_mark: usize,
+}
+
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    classes: [SizeClass; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    /// The maximum size the fallback heap may grow to.
+    heap_limit: usize,
+    /// Counters for each block size class.
+    class_stats: [SizeClassStats; BLOCK_SIZES.len()],
+    /// The number of allocations too large for any size class.
+    large_allocations: u64,
+    /// The number of frees of allocations too large for any size class.
+    large_frees: u64,
+    /// The highest number of bytes ever in use in the fallback heap.
+    high_water_mark: usize,
 }
 
+// single threaded environment
+unsafe impl Send for FixedSizeBlockAllocator {}
+
 impl FixedSizeBlockAllocator {
     /// Creates an empty FixedSizeBlockAllocator.
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
+        const EMPTY: SizeClass = SizeClass {
+            partial: ptr::null_mut(),
+            slabs: 0,
+            empty_slabs: 0,
+        };
+        let mut class_stats = [SizeClassStats {
+            block_size: 0,
+            allocations: 0,
+            frees: 0,
+            cached_blocks: 0,
+            slabs: 0,
+            empty_slabs: 0,
+            released_slabs: 0,
+        }; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            class_stats[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            classes: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            heap_limit: HEAP_MAX_SIZE,
+            class_stats,
+            large_allocations: 0,
+            large_frees: 0,
+            high_water_mark: 0,
+        }
+    }
+
+    /// Returns a snapshot of the allocator's statistics.
+    pub fn stats(&self) -> HeapStats {
+        let mut size_classes = self.class_stats;
+        for (index, stats) in size_classes.iter_mut().enumerate() {
+            let class = &self.classes[index];
+            let in_use = (stats.allocations - stats.frees) as usize;
+            stats.cached_blocks =
+                (class.slabs * blocks_per_slab(index)).saturating_sub(in_use);
+            stats.slabs = class.slabs;
+            stats.empty_slabs = class.empty_slabs;
+        }
+
+        HeapStats {
+            size_classes,
+            large_allocations: self.large_allocations,
+            large_frees: self.large_frees,
+            heap_size: self.fallback_allocator.size(),
+            heap_used: self.fallback_allocator.used(),
+            heap_high_water_mark: self.high_water_mark,
         }
     }
 
@@ -65,57 +236,318 @@ impl FixedSizeBlockAllocator {
             .init(heap_start as *mut u8, heap_size);
     }
 
+    /// Allocates a block from the size class `index`.
+    ///
+    /// Takes the block from the first slab with free blocks, creating a new
+    /// slab if there is none.
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        if self.classes[index].partial.is_null() {
+            let slab = self.alloc_slab(index);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.classes[index].slabs += 1;
+            self.classes[index].empty_slabs += 1;
+            unsafe { self.link_slab(index, slab) };
+        }
+
+        let slab = unsafe { &mut *self.classes[index].partial };
+        let node = slab.free_list.take().expect("partial slab is full");
+        slab.free_list = node.next.take();
+        if slab.used == 0 {
+            self.classes[index].empty_slabs -= 1;
+        }
+        slab.used += 1;
+        if slab.free_list.is_none() {
+            unsafe { self.unlink_slab(index, slab) };
+        }
+
+        node as *mut ListNode as *mut u8
+    }
+
+    /// Returns a block to its slab in the size class `index`.
+    ///
+    /// Fully free slabs beyond [`EMPTY_SLABS_KEPT`] are released to the
+    /// fallback heap.
+    ///
+    /// # Safety
+    /// The block must have been allocated from the same size class.
+    unsafe fn dealloc_block(&mut self, index: usize, ptr: *mut u8) {
+        // verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+        let slab_ptr = (ptr as usize & !(slab_size(index) - 1)) as *mut Slab;
+        let slab = &mut *slab_ptr;
+        let was_full = slab.free_list.is_none();
+
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(ListNode {
+            next: slab.free_list.take(),
+        });
+        slab.free_list = Some(&mut *new_node_ptr);
+        slab.used -= 1;
+
+        if was_full {
+            self.link_slab(index, slab_ptr);
+        }
+        if slab.used == 0 {
+            if self.classes[index].empty_slabs >= EMPTY_SLABS_KEPT {
+                self.release_slab(index, slab_ptr);
+            } else {
+                self.classes[index].empty_slabs += 1;
+            }
+        }
+    }
+
+    /// Allocates a new slab for the size class `index` from the fallback heap
+    /// and carves it into free blocks.
+    fn alloc_slab(&mut self, index: usize) -> *mut Slab {
+        let block_size = BLOCK_SIZES[index];
+        let layout =
+            Layout::from_size_align(slab_size(index), slab_size(index))
+                .unwrap();
+        let memory = self.fallback_alloc(layout);
+        if memory.is_null() {
+            return ptr::null_mut();
+        }
+
+        // every free block must carry poison, see `heap_debug`
+        #[cfg(feature = "debug-heap")]
+        unsafe {
+            super::heap_debug::poison(memory, slab_size(index))
+        };
+
+        // push the blocks in reverse so they are handed out in address order
+        let mut free_list: Option<&'static mut ListNode> = None;
+        for block in (header_blocks(index)..slab_size(index) / block_size).rev()
+        {
+            let node_ptr = unsafe { memory.add(block * block_size) };
+            let node_ptr = node_ptr as *mut ListNode;
+            unsafe {
+                node_ptr.write(ListNode {
+                    next: free_list.take(),
+                });
+                free_list = Some(&mut *node_ptr);
+            }
+        }
+
+        let slab = memory as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                free_list,
+                used: 0,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            })
+        };
+        slab
+    }
+
+    /// Returns an empty slab of the size class `index` to the fallback heap.
+    ///
+    /// # Safety
+    /// The slab must be empty and linked into the class's list.
+    unsafe fn release_slab(&mut self, index: usize, slab: *mut Slab) {
+        self.unlink_slab(index, slab);
+        self.classes[index].slabs -= 1;
+        self.class_stats[index].released_slabs += 1;
+
+        let layout =
+            Layout::from_size_align(slab_size(index), slab_size(index))
+                .unwrap();
+        self.fallback_allocator
+            .deallocate(NonNull::new_unchecked(slab as *mut u8), layout);
+    }
+
+    /// Release every empty slab of every size class to the fallback heap.
+    ///
+    /// Used when the fallback heap runs out of memory, so that a burst of
+    /// allocations in one size class does not starve the others.
+    ///
+    /// # Returns
+    /// `true` if any slab was released.
+    fn reclaim(&mut self) -> bool {
+        let mut released = false;
+        for index in 0..BLOCK_SIZES.len() {
+            let mut slab = self.classes[index].partial;
+            while !slab.is_null() {
+                let next = unsafe { (*slab).next };
+                if unsafe { (*slab).used } == 0 {
+                    unsafe { self.release_slab(index, slab) };
+                    self.classes[index].empty_slabs -= 1;
+                    released = true;
+                }
+                slab = next;
+            }
+        }
+        released
+    }
+
+    /// Push a slab to the front of the list of slabs with free blocks.
+    ///
+    /// # Safety
+    /// The slab must belong to the size class `index` and must not be linked.
+    unsafe fn link_slab(&mut self, index: usize, slab: *mut Slab) {
+        let head = self.classes[index].partial;
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = head;
+        if !head.is_null() {
+            (*head).prev = slab;
+        }
+        self.classes[index].partial = slab;
+    }
+
+    /// Remove a slab from the list of slabs with free blocks.
+    ///
+    /// # Safety
+    /// The slab must be linked into the list of the size class `index`.
+    unsafe fn unlink_slab(&mut self, index: usize, slab: *mut Slab) {
+        let Slab { prev, next, .. } = *slab;
+        if prev.is_null() {
+            self.classes[index].partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
     /// Allocates using the fallback allocator.
+    ///
+    /// If the fallback heap is exhausted, empty slabs are reclaimed first and
+    /// only then the heap is grown by mapping further pages.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
+        let mut result = self.fallback_allocator.allocate_first_fit(layout);
+
+        if result.is_err() && self.reclaim() {
+            result = self.fallback_allocator.allocate_first_fit(layout);
+        }
+        // the new memory may have to be padded to the requested alignment
+        if result.is_err() && self.grow(layout.size() + layout.align()) {
+            result = self.fallback_allocator.allocate_first_fit(layout);
+        }
+
+        self.high_water_mark =
+            self.high_water_mark.max(self.fallback_allocator.used());
+        match result {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    /// Grow the fallback heap by at least `min_size` bytes.
+    ///
+    /// Maps fresh pages directly above the current heap top, as long as the
+    /// heap stays within its limit.
+    ///
+    /// # Returns
+    /// `true` if the heap was grown.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let size =
+            align_up(min_size.max(HEAP_GROWTH) as u64, Size4KiB::SIZE) as usize;
+        if self.fallback_allocator.size() + size > self.heap_limit {
+            return false;
+        }
+
+        // the page table or frame allocator may be locked by the code that
+        // triggered this allocation, so never spin on them here
+        let Some(mut mapper) = paging::try_mapper() else {
+            return false;
+        };
+        let Some(mut frame_allocator) = FRAME_ALLOCATOR.try_lock() else {
+            return false;
+        };
+
+        let top = self.fallback_allocator.top() as usize;
+        let (mapped, _) =
+            map_heap_pages(top, size, &mut *mapper, &mut *frame_allocator);
+        // pages mapped before a failure still belong to the heap, so the next
+        // attempt starts above them
+        if mapped == 0 {
+            return false;
+        }
+
+        unsafe { self.fallback_allocator.extend(mapped) };
+        true
+    }
+
+    /// Set the maximum size the fallback heap may grow to.
+    ///
+    /// Limits below the current heap size only prevent further growth.
+    /// Limits above [`HEAP_MAX_SIZE`] are clamped, so the heap never grows
+    /// into its upper sentinel.
+    pub fn set_heap_limit(&mut self, limit: usize) {
+        self.heap_limit = limit.min(HEAP_MAX_SIZE);
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(any(feature = "debug-heap", feature = "heap-tracking"))]
+        let requested = layout;
+        #[cfg(feature = "debug-heap")]
+        let layout = super::heap_debug::block_layout(layout);
+
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout =
-                            Layout::from_size_align(block_size, block_align)
-                                .unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
+                let ptr = allocator.alloc_block(index);
+                if !ptr.is_null() {
+                    allocator.class_stats[index].allocations += 1;
+                }
+                ptr
+            }
+            None => {
+                let ptr = allocator.fallback_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.large_allocations += 1;
                 }
+                ptr
             }
-            None => allocator.fallback_alloc(layout),
+        };
+        drop(allocator);
+
+        #[cfg(feature = "debug-heap")]
+        let ptr = if ptr.is_null() {
+            ptr
+        } else {
+            let block_size =
+                list_index(&layout).map(|index| BLOCK_SIZES[index]);
+            super::heap_debug::on_alloc(ptr, requested, block_size)
+        };
+
+        #[cfg(feature = "heap-tracking")]
+        if !ptr.is_null() {
+            super::heap_tracking::record_alloc(ptr, requested.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-tracking")]
+        super::heap_tracking::record_dealloc(ptr);
+
+        #[cfg(feature = "debug-heap")]
+        let (ptr, layout) = {
+            let block_layout = super::heap_debug::block_layout(layout);
+            let block_size =
+                list_index(&block_layout).map(|index| BLOCK_SIZES[index]);
+            let block = super::heap_debug::on_dealloc(ptr, layout, block_size);
+            (block, block_layout)
+        };
+
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing
-                // node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.class_stats[index].frees += 1;
+                allocator.dealloc_block(index, ptr);
             }
             None => {
+                allocator.large_frees += 1;
                 let ptr = NonNull::new(ptr).unwrap();
                 allocator.fallback_allocator.deallocate(ptr, layout);
             }
@@ -127,29 +559,172 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
     Locked::new(FixedSizeBlockAllocator::new());
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+/// Map the initial kernel heap and initialize the global allocator.
+///
+/// Uses the kernel's page table and the global frame allocator, so both must
+/// be initialized first. The pages directly around the heap's window are
+/// registered as guard regions, so stray accesses before or past the heap
+/// fault and are reported as heap overruns.
+///
+/// # Panics
+/// Panics if one of the sentinel pages is mapped.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let lower_guard = VirtAddr::new((HEAP_START - HEAP_GUARD_SIZE) as u64);
+    let upper_guard = VirtAddr::new((HEAP_START + HEAP_MAX_SIZE) as u64);
+    for guard in [lower_guard, upper_guard] {
+        assert!(
+            !paging::walk(guard).is_mapped(),
+            "heap sentinel at {:#x} is mapped",
+            guard.as_u64()
+        );
     }
+    guard::register(
+        lower_guard,
+        HEAP_GUARD_SIZE as u64,
+        GuardKind::HeapUnderflow,
+        "kernel heap",
+    );
+    guard::register(
+        upper_guard,
+        HEAP_GUARD_SIZE as u64,
+        GuardKind::HeapOverflow,
+        "kernel heap",
+    );
+
+    map_heap_pages(
+        HEAP_START,
+        HEAP_INITIAL_SIZE,
+        &mut *paging::mapper(),
+        &mut *FRAME_ALLOCATOR.lock(),
+    )
+    .1?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
 }
+
+/// Set the maximum size the kernel heap may grow to.
+///
+/// # Arguments
+/// * `limit` - The heap ceiling in bytes.
+pub fn set_heap_limit(limit: usize) {
+    ALLOCATOR.lock().set_heap_limit(limit);
+}
+
+/// Returns a snapshot of the global allocator's statistics.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Log the global allocator's statistics.
+///
+/// # Arguments
+/// * `level` - The log level to use.
+pub fn log_heap_stats(level: log::Level) {
+    let stats = heap_stats();
+
+    log::log!(
+        level,
+        "Heap: {} of {} bytes used (high water mark {} bytes)",
+        stats.heap_used,
+        stats.heap_size,
+        stats.heap_high_water_mark
+    );
+    for class in stats.size_classes.iter() {
+        log::log!(
+            level,
+            "  {:>4} B blocks: {} allocs, {} frees, {} cached in {} slabs ({} \
+             empty, {} released)",
+            class.block_size,
+            class.allocations,
+            class.frees,
+            class.cached_blocks,
+            class.slabs,
+            class.empty_slabs,
+            class.released_slabs
+        );
+    }
+    log::log!(
+        level,
+        "  large allocations: {} allocs, {} frees",
+        stats.large_allocations,
+        stats.large_frees
+    );
+}
+
+/// Map fresh writable pages for the heap.
+///
+/// Parts of the range that cover a whole 2 MiB aligned block are mapped with
+/// a single 2 MiB page if a 2 MiB frame is available.
+///
+/// # Arguments
+/// * `start` - The page aligned start address of the range.
+/// * `size` - The size of the range in bytes.
+/// * `mapper` - The mapper to use for mapping
+/// * `frame_allocator` - The frame allocator to use for allocating frames
+///
+/// # Returns
+/// The number of bytes mapped from `start` on, and the error that stopped
+/// the mapping early, if any. The pages mapped before the error stay mapped.
+fn map_heap_pages<M, A>(
+    start: usize,
+    size: usize,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> (usize, Result<(), MapToError<Size4KiB>>)
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let end = VirtAddr::new((start + size) as u64);
+    let mut addr = VirtAddr::new(start as u64);
+
+    while addr < end {
+        if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            if let Some(frame) =
+                FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator)
+            {
+                match unsafe {
+                    mapper.map_to(page, frame, flags, frame_allocator)
+                } {
+                    Ok(flush) => {
+                        flush.flush();
+                        addr += Size2MiB::SIZE;
+                        continue;
+                    }
+                    // part of the block already has a page table, fall back
+                    // to 4 KiB pages
+                    Err(_) => unsafe {
+                        frame_allocator.deallocate_frame(frame)
+                    },
+                }
+            }
+        }
+
+        let mapped = (addr.as_u64() as usize) - start;
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let Some(frame) =
+            FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+        else {
+            return (mapped, Err(MapToError::FrameAllocationFailed));
+        };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return (mapped, Err(error));
+            }
+        }
+        addr += Size4KiB::SIZE;
+    }
+
+    (size, Ok(()))
+}
@@ -0,0 +1,173 @@
+//! Boot memory map report and reclamation
+//!
+//! Logs the memory map handed over by the bootloader, and gives the frames
+//! of bootloader owned regions that the kernel does not use to the frame
+//! allocator once booting is done.
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging};
+
+/// The number of bytes in a mebibyte, for the report.
+const MIB: u64 = 1024 * 1024;
+
+/// Log every region of the boot memory map and the totals per kind.
+///
+/// # Arguments
+/// * `regions` - The memory regions passed by the bootloader.
+/// * `level` - The log level to use.
+pub fn log_memory_map(regions: &[MemoryRegion], level: log::Level) {
+    log::log!(level, "Boot memory map:");
+    for region in regions {
+        log::log!(
+            level,
+            "  {:#014x}-{:#014x} {:>10} KiB {:?}",
+            region.start,
+            region.end - 1,
+            (region.end - region.start) / 1024,
+            region.kind
+        );
+    }
+
+    let total = |kind: fn(&MemoryRegionKind) -> bool| {
+        regions
+            .iter()
+            .filter(|region| kind(&region.kind))
+            .map(|region| region.end - region.start)
+            .sum::<u64>()
+    };
+    let usable = total(|kind| *kind == MemoryRegionKind::Usable);
+    let bootloader = total(|kind| *kind == MemoryRegionKind::Bootloader);
+    let uefi = total(|kind| matches!(kind, MemoryRegionKind::UnknownUefi(_)));
+    let bios = total(|kind| matches!(kind, MemoryRegionKind::UnknownBios(_)));
+    log::log!(
+        level,
+        "  usable {} MiB, bootloader {} MiB, UEFI {} MiB, BIOS {} MiB",
+        usable / MIB,
+        bootloader / MIB,
+        uefi / MIB,
+        bios / MIB
+    );
+}
+
+/// A bootloader region and which of its frames are still referenced.
+struct BootloaderRegion {
+    /// The first frame of the region.
+    start: u64,
+    /// The end (exclusive) of the region, in frames.
+    end: u64,
+    /// One bit per frame, set if the frame is referenced.
+    referenced: Vec<u64>,
+}
+
+impl BootloaderRegion {
+    /// Mark the frames of `[start, start + size)` that lie in the region as
+    /// referenced.
+    fn mark(&mut self, start: PhysAddr, size: u64) {
+        let first = (start.as_u64() / Size4KiB::SIZE).max(self.start);
+        let end = (start.as_u64() + size)
+            .div_ceil(Size4KiB::SIZE)
+            .min(self.end);
+        for frame in first..end {
+            let bit = (frame - self.start) as usize;
+            self.referenced[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `true` if the frame with the given number is referenced.
+    fn is_referenced(&self, frame: u64) -> bool {
+        let bit = (frame - self.start) as usize;
+        self.referenced[bit / 64] & (1 << (bit % 64)) != 0
+    }
+}
+
+/// Give the frames of bootloader regions that are no longer used to the
+/// frame allocator.
+///
+/// The bootloader marks everything it allocated as [`Bootloader`] memory:
+/// the kernel image, the boot stack, the boot info and the page tables, but
+/// also the kernel's ELF file and its own temporary structures. A frame is
+/// kept if the active page table maps it anywhere outside of the physical
+/// memory window, or holds one of the page tables themselves. Everything else
+/// is reclaimed.
+///
+/// Must be called once booting is done and the active page table is the
+/// kernel's.
+///
+/// [`Bootloader`]: MemoryRegionKind::Bootloader
+///
+/// # Arguments
+/// * `regions` - The memory regions passed by the bootloader.
+///
+/// # Returns
+/// The number of frames reclaimed.
+pub fn reclaim_bootloader_memory(regions: &[MemoryRegion]) -> usize {
+    let mut bootloader_regions: Vec<BootloaderRegion> = regions
+        .iter()
+        .filter(|region| region.kind == MemoryRegionKind::Bootloader)
+        .map(|region| {
+            // only whole frames, a partial frame is shared with a neighbour
+            let start = region.start.div_ceil(Size4KiB::SIZE);
+            let end = (region.end / Size4KiB::SIZE).max(start);
+            BootloaderRegion {
+                start,
+                end,
+                referenced: vec![0; ((end - start) as usize).div_ceil(64)],
+            }
+        })
+        .collect();
+
+    // the physical memory window maps every frame, so it says nothing about
+    // which frames are in use
+    let window_start = paging::physical_memory_offset();
+    let window_end = window_start
+        + regions.iter().map(|region| region.end).max().unwrap_or(0);
+    let in_window = |virt: VirtAddr| virt >= window_start && virt < window_end;
+
+    paging::for_each_mapping(|range| {
+        if in_window(range.virt) {
+            return;
+        }
+        for region in bootloader_regions.iter_mut() {
+            region.mark(range.phys, range.size);
+        }
+    });
+    paging::for_each_page_table(|frame| {
+        for region in bootloader_regions.iter_mut() {
+            region.mark(frame.start_address(), Size4KiB::SIZE);
+        }
+    });
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mut reclaimed = 0;
+    for region in bootloader_regions.iter() {
+        let mut frame = region.start;
+        while frame < region.end {
+            if region.is_referenced(frame) {
+                frame += 1;
+                continue;
+            }
+            let run_start = frame;
+            while frame < region.end && !region.is_referenced(frame) {
+                frame += 1;
+            }
+            let range = PhysFrame::range(
+                PhysFrame::containing_address(PhysAddr::new(
+                    run_start * Size4KiB::SIZE,
+                )),
+                PhysFrame::containing_address(PhysAddr::new(
+                    frame * Size4KiB::SIZE,
+                )),
+            );
+            reclaimed += unsafe { frame_allocator.add_frames(range) };
+        }
+    }
+    reclaimed
+}
@@ -0,0 +1,242 @@
+//! Demand paged memory regions
+//!
+//! Subsystems can register ranges of virtual memory that are not backed by
+//! any frames up front. The first access to a page in such a region causes a
+//! page fault, and the page fault handler maps a zeroed frame for it.
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+            PageTableFlags, Size4KiB,
+        },
+    },
+    VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging, vmm, Locked};
+
+/// The registered demand paged regions.
+static REGIONS: Locked<Vec<LazyRegion>> = Locked::new(Vec::new());
+
+/// A range of virtual memory that is backed by zeroed frames on first touch.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    /// The first address of the region.
+    pub start: VirtAddr,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// The flags pages of the region are mapped with.
+    pub flags: PageTableFlags,
+    /// A name for the region, used in fault reports.
+    pub name: &'static str,
+    /// Whether the virtual range was allocated by [`allocate`].
+    owns_range: bool,
+}
+
+impl LazyRegion {
+    /// Returns `true` if `addr` lies within the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr - self.start < self.size
+    }
+
+    /// Returns the pages of the region.
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.start + (self.size - 1));
+        Page::range_inclusive(start, end)
+    }
+}
+
+/// An error that occurred while registering a demand paged region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError {
+    /// The region is empty or not page aligned.
+    InvalidRange,
+    /// The region overlaps an already registered region.
+    Overlap,
+    /// The kernel virtual region is exhausted.
+    OutOfVirtualMemory,
+}
+
+/// Register a demand paged region.
+///
+/// # Arguments
+/// * `start` - The page aligned start address of the region.
+/// * `size` - The size of the region in bytes, a multiple of the page size.
+/// * `flags` - The flags pages of the region are mapped with. `PRESENT` is
+///   always added.
+/// * `name` - A name for the region, used in fault reports.
+pub fn register(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+) -> Result<(), LazyRegionError> {
+    insert(start, size, flags, name, false)
+}
+
+/// Validate a region and add it to the region list.
+fn insert(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    name: &'static str,
+    owns_range: bool,
+) -> Result<(), LazyRegionError> {
+    if size == 0
+        || !start.is_aligned(Size4KiB::SIZE)
+        || size % Size4KiB::SIZE != 0
+    {
+        return Err(LazyRegionError::InvalidRange);
+    }
+
+    let region = LazyRegion {
+        start,
+        size,
+        flags: flags | PageTableFlags::PRESENT,
+        name,
+        owns_range,
+    };
+    let mut regions = REGIONS.lock();
+    if regions.iter().any(|other| {
+        region.start < other.start + other.size
+            && other.start < region.start + region.size
+    }) {
+        return Err(LazyRegionError::Overlap);
+    }
+    regions.push(region);
+    Ok(())
+}
+
+/// Allocate a demand paged region in the kernel virtual region.
+///
+/// The region is writable and not executable.
+///
+/// # Arguments
+/// * `size` - The size of the region in bytes.
+/// * `name` - A name for the region, used in fault reports.
+///
+/// # Returns
+/// The start address of the region.
+pub fn allocate(
+    size: u64,
+    name: &'static str,
+) -> Result<VirtAddr, LazyRegionError> {
+    let size = x86_64::align_up(size, Size4KiB::SIZE);
+    let start = vmm::allocate(size, Size4KiB::SIZE)
+        .ok_or(LazyRegionError::OutOfVirtualMemory)?;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    insert(start, size, flags, name, true).inspect_err(|_| {
+        vmm::deallocate(start, size);
+    })?;
+    Ok(start)
+}
+
+/// Unregister the demand paged region starting at `start`.
+///
+/// Unmaps every page of the region that has been touched and frees its
+/// frame. Regions created with [`allocate`] also give back their virtual
+/// range.
+///
+/// # Safety
+/// The caller must ensure that the region's memory is no longer used.
+pub unsafe fn unregister(start: VirtAddr) -> Option<LazyRegion> {
+    let region = {
+        let mut regions = REGIONS.lock();
+        let index = regions.iter().position(|region| region.start == start)?;
+        regions.swap_remove(index)
+    };
+
+    let mut mapper = paging::mapper();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for page in region.pages() {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    drop(frame_allocator);
+    drop(mapper);
+
+    if region.owns_range {
+        vmm::deallocate(region.start, region.size);
+    }
+    Some(region)
+}
+
+/// Returns the demand paged region containing `addr`, if any.
+///
+/// Returns `None` if the region list is currently locked.
+pub fn find(addr: VirtAddr) -> Option<LazyRegion> {
+    REGIONS
+        .try_lock()?
+        .iter()
+        .find(|region| region.contains(addr))
+        .copied()
+}
+
+/// Try to resolve a page fault by backing the faulting page with a zeroed
+/// frame.
+///
+/// Called by the page fault handler. Never spins on a lock, since the fault
+/// may have interrupted code holding it.
+///
+/// # Arguments
+/// * `addr` - The faulting address.
+/// * `error_code` - The page fault error code.
+///
+/// # Returns
+/// `true` if the page was mapped and the faulting access can be retried.
+pub(crate) fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> bool {
+    // only accesses to pages that are not present yet can be fixed up
+    if error_code.intersects(
+        PageFaultErrorCode::PROTECTION_VIOLATION
+            | PageFaultErrorCode::USER_MODE
+            | PageFaultErrorCode::MALFORMED_TABLE,
+    ) {
+        return false;
+    }
+    let Some(region) = find(addr) else {
+        return false;
+    };
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return false;
+    }
+
+    let Some(mut mapper) = paging::try_mapper() else {
+        return false;
+    };
+    let Some(mut frame_allocator) = FRAME_ALLOCATOR.try_lock() else {
+        return false;
+    };
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return false;
+    };
+
+    unsafe {
+        let frame_ptr: *mut u8 = (paging::physical_memory_offset()
+            + frame.start_address().as_u64())
+        .as_mut_ptr();
+        frame_ptr.write_bytes(0, Size4KiB::SIZE as usize);
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match mapper.map_to(page, frame, region.flags, &mut *frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                frame_allocator.deallocate_frame(frame);
+                return false;
+            }
+        }
+    }
+    true
+}
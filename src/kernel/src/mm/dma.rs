@@ -0,0 +1,376 @@
+//! DMA memory
+//!
+//! Devices access memory by physical address and usually need a buffer to be
+//! physically contiguous, aligned and sometimes placed below an address limit
+//! or within a boundary they cannot cross. [`DmaBuffer`] allocates such
+//! buffers directly from the frame allocator and reaches them through the
+//! physical memory window, so no mapping is needed. [`DmaPool`] carves many
+//! small blocks of one size, such as descriptors, out of page sized buffers.
+//!
+//! x86 keeps DMA coherent with the caches, so the memory is mapped
+//! write-back.
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{fmt, slice};
+
+use x86_64::{
+    align_down, align_up,
+    structures::paging::{frame::PhysFrameRange, PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging, Locked};
+
+/// The placement requirements of a DMA allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The alignment of the physical address in bytes. Must be a power of
+    /// two.
+    pub align: usize,
+    /// The allocation must not cross a multiple of this many bytes. Must be
+    /// zero (no boundary) or a power of two.
+    pub boundary: usize,
+    /// The (exclusive) physical address the allocation must end below.
+    pub max_address: u64,
+}
+
+impl DmaConstraints {
+    /// Page aligned, no boundary and no address limit.
+    pub const NONE: Self = DmaConstraints {
+        align: Size4KiB::SIZE as usize,
+        boundary: 0,
+        max_address: u64::MAX,
+    };
+
+    /// Like [`Self::NONE`], but below 4 GiB for devices with 32 bit
+    /// addressing.
+    pub const BELOW_4GIB: Self = DmaConstraints {
+        max_address: 1 << 32,
+        ..Self::NONE
+    };
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// An error that occurred while allocating DMA memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The size is zero, or the constraints are invalid or cannot be met by
+    /// any allocation of the requested size.
+    InvalidConstraints,
+    /// No free physical memory satisfies the constraints.
+    OutOfMemory,
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::InvalidConstraints => write!(f, "invalid constraints"),
+            DmaError::OutOfMemory => write!(f, "out of physical memory"),
+        }
+    }
+}
+
+/// A physically contiguous, zeroed buffer for DMA.
+///
+/// Dropping the buffer frees its frames. The device must no longer access it
+/// by then.
+pub struct DmaBuffer {
+    /// The frames backing the buffer.
+    frames: PhysFrameRange<Size4KiB>,
+    /// The size requested by the caller in bytes.
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocate a buffer.
+    ///
+    /// # Arguments
+    /// * `size` - The size of the buffer in bytes. Rounded up to whole frames.
+    /// * `constraints` - The placement requirements of the device.
+    pub fn new(
+        size: usize,
+        constraints: DmaConstraints,
+    ) -> Result<Self, DmaError> {
+        let DmaConstraints {
+            align,
+            boundary,
+            max_address,
+        } = constraints;
+        if size == 0
+            || !align.is_power_of_two()
+            || (boundary != 0
+                && (!boundary.is_power_of_two() || size > boundary))
+        {
+            return Err(DmaError::InvalidConstraints);
+        }
+
+        let frame_size = Size4KiB::SIZE as usize;
+        let count = size.div_ceil(frame_size);
+        let align = (align / frame_size).max(1);
+        // a boundary below the frame size is never crossed by a frame aligned
+        // buffer that fits it
+        let boundary = boundary / frame_size;
+
+        let frames = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous_below(count, align, boundary, max_address)
+            .ok_or(DmaError::OutOfMemory)?;
+        let buffer = DmaBuffer { frames, size };
+        unsafe {
+            buffer.as_ptr::<u8>().write_bytes(0, count * frame_size);
+        }
+        Ok(buffer)
+    }
+
+    /// Returns the physical address of the buffer, to be handed to the
+    /// device.
+    pub fn phys(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Returns the virtual address of the buffer.
+    pub fn virt(&self) -> VirtAddr {
+        paging::physical_memory_offset() + self.phys().as_u64()
+    }
+
+    /// Returns the size of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns a pointer to the start of the buffer.
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt().as_mut_ptr()
+    }
+
+    /// Returns the contents of the buffer.
+    ///
+    /// The device may write the buffer at any time, so the contents are only
+    /// meaningful once it has signalled completion.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size) }
+    }
+
+    /// Returns the contents of the buffer mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_contiguous(self.frames) };
+    }
+}
+
+impl fmt::Debug for DmaBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("virt", &self.virt())
+            .field("phys", &self.phys())
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// The mutable state of a [`DmaPool`].
+struct PoolState {
+    /// The buffers the blocks are carved from.
+    chunks: Vec<DmaBuffer>,
+    /// The physical addresses of the free blocks.
+    free: Vec<PhysAddr>,
+}
+
+/// A pool of equally sized DMA blocks.
+///
+/// Blocks are carved out of [`DmaBuffer`]s that are allocated as the pool
+/// grows and only freed when the pool is dropped.
+pub struct DmaPool {
+    /// The size of a block in bytes.
+    block_size: usize,
+    /// The distance between the starts of neighbouring blocks.
+    stride: usize,
+    /// The size of the buffers blocks are carved from.
+    chunk_size: usize,
+    /// The constraints of every block.
+    constraints: DmaConstraints,
+    /// The chunks and the free list.
+    state: Locked<PoolState>,
+}
+
+impl DmaPool {
+    /// Create an empty pool.
+    ///
+    /// # Arguments
+    /// * `block_size` - The size of a block in bytes.
+    /// * `constraints` - The placement requirements of every block.
+    pub fn new(
+        block_size: usize,
+        constraints: DmaConstraints,
+    ) -> Result<Self, DmaError> {
+        let DmaConstraints {
+            align, boundary, ..
+        } = constraints;
+        if block_size == 0
+            || !align.is_power_of_two()
+            || (boundary != 0
+                && (!boundary.is_power_of_two() || block_size > boundary))
+        {
+            return Err(DmaError::InvalidConstraints);
+        }
+
+        let stride = align_up(block_size as u64, align as u64) as usize;
+        let chunk_size = align_up(stride as u64, Size4KiB::SIZE) as usize;
+        Ok(DmaPool {
+            block_size,
+            stride,
+            chunk_size,
+            constraints,
+            state: Locked::new(PoolState {
+                chunks: Vec::new(),
+                free: Vec::new(),
+            }),
+        })
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Allocate a zeroed block, growing the pool if no block is free.
+    pub fn alloc(&self) -> Result<DmaBlock<'_>, DmaError> {
+        let mut state = self.state.lock();
+        if state.free.is_empty() {
+            self.grow(&mut state)?;
+        }
+        let phys = state.free.pop().ok_or(DmaError::OutOfMemory)?;
+        drop(state);
+
+        let block = DmaBlock { pool: self, phys };
+        unsafe { block.as_ptr::<u8>().write_bytes(0, self.block_size) };
+        Ok(block)
+    }
+
+    /// Allocate another chunk and add its blocks to the free list.
+    fn grow(&self, state: &mut PoolState) -> Result<(), DmaError> {
+        let constraints = DmaConstraints {
+            align: self.constraints.align.max(Size4KiB::SIZE as usize),
+            // blocks are kept off the boundary below, the chunk only has to
+            // stay clear of it if it is at least a frame
+            boundary: match self.constraints.boundary {
+                boundary if boundary >= self.chunk_size => boundary,
+                _ => 0,
+            },
+            max_address: self.constraints.max_address,
+        };
+        let chunk = DmaBuffer::new(self.chunk_size, constraints)?;
+
+        let start = chunk.phys().as_u64();
+        let boundary = self.constraints.boundary as u64;
+        let mut offset = 0;
+        while offset + self.block_size <= self.chunk_size {
+            let block = start + offset as u64;
+            let end = block + self.block_size as u64 - 1;
+            if boundary == 0 || block / boundary == end / boundary {
+                state.free.push(PhysAddr::new(block));
+                offset += self.stride;
+            } else {
+                // restart at the boundary the block crossed
+                let next = align_up(
+                    align_down(end, boundary),
+                    self.constraints.align as u64,
+                );
+                offset = (next - start) as usize;
+            }
+        }
+        state.chunks.push(chunk);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DmaPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaPool")
+            .field("block_size", &self.block_size)
+            .field("constraints", &self.constraints)
+            .field("chunks", &self.state.lock().chunks.len())
+            .finish()
+    }
+}
+
+/// A block allocated from a [`DmaPool`].
+///
+/// Dropping the block returns it to the pool. The device must no longer
+/// access it by then.
+pub struct DmaBlock<'a> {
+    /// The pool the block belongs to.
+    pool: &'a DmaPool,
+    /// The physical address of the block.
+    phys: PhysAddr,
+}
+
+impl DmaBlock<'_> {
+    /// Returns the physical address of the block, to be handed to the
+    /// device.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the virtual address of the block.
+    pub fn virt(&self) -> VirtAddr {
+        paging::physical_memory_offset() + self.phys.as_u64()
+    }
+
+    /// Returns the size of the block in bytes.
+    pub fn len(&self) -> usize {
+        self.pool.block_size
+    }
+
+    /// Returns `true` if the block is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a pointer to the start of the block.
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt().as_mut_ptr()
+    }
+
+    /// Returns the contents of the block.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+
+    /// Returns the contents of the block mutably.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBlock<'_> {
+    fn drop(&mut self) {
+        self.pool.state.lock().free.push(self.phys);
+    }
+}
+
+impl fmt::Debug for DmaBlock<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DmaBlock")
+            .field("virt", &self.virt())
+            .field("phys", &self.phys)
+            .field("size", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,398 @@
+//! Physical frame allocator
+//!
+//! Keeps one bit per 4 KiB physical frame in a bitmap that is carved out of
+//! the first usable region large enough to hold it. The bitmap is accessed
+//! through the bootloader's physical memory mapping, so the allocator does not
+//! depend on the kernel heap and can be used to set it up. A second bitmap
+//! records which frames were handed to the allocator, so that freeing a
+//! reserved frame cannot put it into circulation.
+use core::slice;
+
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::{
+    align_up,
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize,
+        PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::Locked;
+
+/// Size of a single frame tracked by the bitmap.
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Number of frames tracked by a single bitmap word.
+const FRAMES_PER_WORD: usize = u64::BITS as usize;
+
+/// The global physical frame allocator.
+pub static FRAME_ALLOCATOR: Locked<BitmapFrameAllocator> =
+    Locked::new(BitmapFrameAllocator::new());
+
+/// A physical frame allocator backed by a bitmap.
+///
+/// A set bit marks a frame that is in use (or not usable at all), a cleared
+/// bit marks a free frame.
+pub struct BitmapFrameAllocator {
+    /// One bit per frame, starting at physical address 0.
+    bitmap: &'static mut [u64],
+    /// One bit per frame, set for the frames handed to the allocator, which
+    /// are the only ones that may be freed.
+    managed: &'static mut [u64],
+    /// The number of frames covered by the bitmap.
+    frame_count: usize,
+    /// The number of usable frames handed to the allocator.
+    usable_frames: usize,
+    /// The number of frames that are currently free.
+    free_frames: usize,
+    /// The bitmap word to start the next search from.
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates an empty BitmapFrameAllocator that has no frames to hand out.
+    pub const fn new() -> Self {
+        BitmapFrameAllocator {
+            bitmap: &mut [],
+            managed: &mut [],
+            frame_count: 0,
+            usable_frames: 0,
+            free_frames: 0,
+            next: 0,
+        }
+    }
+
+    /// Initialize the allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the memory map is valid, that all
+    /// physical memory is mapped at `physical_memory_offset` and that the
+    /// usable regions are not in use. This method must be called only once.
+    ///
+    /// # Arguments
+    /// * `memory_regions` - The memory regions from the bootloader.
+    /// * `physical_memory_offset` - The offset of the physical memory.
+    pub unsafe fn init(
+        &mut self,
+        memory_regions: &MemoryRegions,
+        physical_memory_offset: VirtAddr,
+    ) {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+        };
+
+        // also cover the bootloader's regions, which can be handed to the
+        // allocator later with `add_frames`
+        let max_address = memory_regions
+            .iter()
+            .filter(|region| {
+                matches!(
+                    region.kind,
+                    MemoryRegionKind::Usable | MemoryRegionKind::Bootloader
+                )
+            })
+            .map(|region| region.end)
+            .max()
+            .expect("no usable memory regions");
+        let frame_count = max_address.div_ceil(FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(FRAMES_PER_WORD);
+        // the bitmap is followed by the bitmap of managed frames
+        let bitmap_size = (2 * words * size_of::<u64>()) as u64;
+
+        // place the bitmap at the start of the first region that can hold it
+        let bitmap_start = usable_regions()
+            .map(|region| align_up(region.start, FRAME_SIZE)..region.end)
+            .find(|range| range.end.saturating_sub(range.start) >= bitmap_size)
+            .expect("no usable region large enough for the frame bitmap")
+            .start;
+
+        let bitmap_ptr =
+            (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        self.bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        self.bitmap.fill(u64::MAX);
+        self.managed = slice::from_raw_parts_mut(bitmap_ptr.add(words), words);
+        self.managed.fill(0);
+        self.frame_count = frame_count;
+
+        for region in usable_regions() {
+            let start = align_up(region.start, FRAME_SIZE) / FRAME_SIZE;
+            let end = region.end / FRAME_SIZE;
+            for frame in start..end {
+                self.clear(frame as usize);
+                self.set_managed(frame as usize, true);
+            }
+            self.usable_frames += end.saturating_sub(start) as usize;
+        }
+
+        // the bitmap itself lives in usable memory
+        let bitmap_frames = bitmap_size.div_ceil(FRAME_SIZE);
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames as usize {
+            self.set(frame);
+            self.set_managed(frame, false);
+        }
+
+        // never hand out the null frame
+        if !self.is_used(0) {
+            self.set(0);
+        }
+        self.set_managed(0, false);
+
+        self.free_frames = (0..self.frame_count)
+            .filter(|&frame| !self.is_used(frame))
+            .count();
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of usable frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// Returns the number of usable frames handed to the allocator.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Hand frames that were not usable at boot over to the allocator.
+    ///
+    /// Frames outside of the range covered by the bitmap and frames that are
+    /// already free are skipped.
+    ///
+    /// # Safety
+    /// The caller must ensure that the frames are RAM and no longer in use.
+    ///
+    /// # Returns
+    /// The number of frames added.
+    pub unsafe fn add_frames(
+        &mut self,
+        range: PhysFrameRange<Size4KiB>,
+    ) -> usize {
+        let mut added = 0;
+        for frame in range {
+            let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+            if frame == 0 || frame >= self.frame_count || !self.is_used(frame) {
+                continue;
+            }
+            self.clear(frame);
+            self.set_managed(frame, true);
+            added += 1;
+        }
+        self.usable_frames += added;
+        self.free_frames += added;
+        self.next = 0;
+        added
+    }
+
+    /// Allocate `count` physically contiguous frames.
+    ///
+    /// # Arguments
+    /// * `count` - The number of 4 KiB frames to allocate.
+    /// * `align` - The alignment of the first frame, in frames. Must be a power
+    ///   of two.
+    ///
+    /// # Returns
+    /// The allocated frame range, or `None` if no suitable run of free frames
+    /// exists.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        self.allocate_contiguous_below(count, align, 0, u64::MAX)
+    }
+
+    /// Allocate `count` physically contiguous frames that end below
+    /// `max_address` and do not cross a `boundary`.
+    ///
+    /// # Arguments
+    /// * `count` - The number of 4 KiB frames to allocate.
+    /// * `align` - The alignment of the first frame, in frames. Must be a power
+    ///   of two.
+    /// * `boundary` - The run must not cross a multiple of this many frames.
+    ///   Must be zero (no boundary) or a power of two of at least `count`.
+    /// * `max_address` - The (exclusive) physical address the run must end
+    ///   below.
+    ///
+    /// # Returns
+    /// The allocated frame range, or `None` if no suitable run of free frames
+    /// exists.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        boundary: usize,
+        max_address: u64,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        let limit = self.frame_count.min((max_address / FRAME_SIZE) as usize);
+        let start = self.find_free_run(count, align, boundary, 0..limit)?;
+        for frame in start..start + count {
+            self.set(frame);
+        }
+        self.free_frames -= count;
+
+        Some(PhysFrame::range(frame_at(start), frame_at(start + count)))
+    }
+
+    /// Free a range of frames returned by [`Self::allocate_contiguous`].
+    ///
+    /// # Safety
+    /// The caller must ensure that none of the frames are still in use.
+    pub unsafe fn deallocate_contiguous(
+        &mut self,
+        range: PhysFrameRange<Size4KiB>,
+    ) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Find the first run of `count` free frames within `frames` whose start
+    /// is aligned to `align` frames and that does not cross a multiple of
+    /// `boundary` frames (unless `boundary` is zero).
+    fn find_free_run(
+        &self,
+        count: usize,
+        align: usize,
+        boundary: usize,
+        frames: core::ops::Range<usize>,
+    ) -> Option<usize> {
+        debug_assert!(align.is_power_of_two());
+        debug_assert!(boundary == 0 || boundary.is_power_of_two());
+        if count == 0 || (boundary != 0 && count > boundary) {
+            return None;
+        }
+
+        let mut start = align_up(frames.start as u64, align as u64) as usize;
+        while start + count <= frames.end {
+            // move to the next boundary if the run would cross one
+            if boundary != 0
+                && start / boundary != (start + count - 1) / boundary
+            {
+                start = align_up(start as u64 + 1, boundary as u64) as usize;
+                start = align_up(start as u64, align as u64) as usize;
+                continue;
+            }
+            // skip past the last used frame of the candidate run
+            match (start..start + count).rev().find(|&f| self.is_used(f)) {
+                Some(used) => {
+                    start = align_up(used as u64 + 1, align as u64) as usize
+                }
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Returns `true` if the given frame is allocated or unusable.
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / FRAMES_PER_WORD] & (1 << (frame % FRAMES_PER_WORD))
+            != 0
+    }
+
+    /// Mark a frame as used.
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / FRAMES_PER_WORD] |= 1 << (frame % FRAMES_PER_WORD);
+    }
+
+    /// Mark a frame as free.
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / FRAMES_PER_WORD] &=
+            !(1 << (frame % FRAMES_PER_WORD));
+    }
+
+    /// Returns `true` if the given frame was handed to the allocator, i.e. it
+    /// is covered by the bitmap and lies in a usable region or a reclaimed
+    /// bootloader region.
+    fn is_managed(&self, frame: usize) -> bool {
+        frame < self.frame_count
+            && self.managed[frame / FRAMES_PER_WORD]
+                & (1 << (frame % FRAMES_PER_WORD))
+                != 0
+    }
+
+    /// Mark a frame as handed to the allocator or not.
+    fn set_managed(&mut self, frame: usize, managed: bool) {
+        let bit = 1 << (frame % FRAMES_PER_WORD);
+        if managed {
+            self.managed[frame / FRAMES_PER_WORD] |= bit;
+        } else {
+            self.managed[frame / FRAMES_PER_WORD] &= !bit;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let words = self.bitmap.len();
+        let word = (0..words)
+            .map(|offset| (self.next + offset) % words)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+
+        let frame =
+            word * FRAMES_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+        if frame >= self.frame_count {
+            return None;
+        }
+
+        self.set(frame);
+        self.free_frames -= 1;
+        self.next = word;
+
+        Some(frame_at(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        // reserved frames were never handed out and must stay in use
+        let managed = self.is_managed(frame);
+        debug_assert!(managed, "freeing unmanaged frame {frame:#x}");
+        if !managed {
+            return;
+        }
+        assert!(self.is_used(frame), "double free of frame {frame:#x}");
+
+        self.clear(frame);
+        self.free_frames += 1;
+        self.next = self.next.min(frame / FRAMES_PER_WORD);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        const FRAMES: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+        let range = self.allocate_contiguous(FRAMES, FRAMES)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        let end = PhysFrame::containing_address(
+            frame.start_address() + Size2MiB::SIZE,
+        );
+        self.deallocate_contiguous(PhysFrame::range(start, end));
+    }
+}
+
+impl Default for BitmapFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the 4 KiB frame with the given index.
+fn frame_at(index: usize) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
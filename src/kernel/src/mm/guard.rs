@@ -0,0 +1,129 @@
+//! Guard regions
+//!
+//! Keeps track of the unmapped pages placed around kernel stacks and the
+//! kernel heap, so that a fault on one of them can be reported as a stack
+//! overflow or a heap overrun instead of a plain page fault.
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use super::Locked;
+use crate::interrupts::{gdt::MAX_STACKS_PER_CPU, stats::MAX_CPUS};
+
+/// The number of guard regions registered once at boot: below and above the
+/// heap, and below the boot stack.
+const FIXED_GUARDS: usize = 3;
+
+/// The maximum number of guard regions that can be registered, enough for
+/// every stack of every CPU (see [`crate::interrupts::gdt::init_cpu`]).
+const MAX_GUARDS: usize = MAX_CPUS * MAX_STACKS_PER_CPU + FIXED_GUARDS;
+
+static GUARDS: Locked<[Option<GuardRegion>; MAX_GUARDS]> =
+    Locked::new([None; MAX_GUARDS]);
+
+/// What a guard region protects against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardKind {
+    /// Sits below a stack, hit when the stack overflows.
+    StackOverflow,
+    /// Sits below the heap, hit when memory before the heap is accessed.
+    HeapUnderflow,
+    /// Sits above the heap, hit when memory past the heap is accessed.
+    HeapOverflow,
+}
+
+impl fmt::Display for GuardKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuardKind::StackOverflow => write!(f, "stack overflow"),
+            GuardKind::HeapUnderflow => write!(f, "heap underflow"),
+            GuardKind::HeapOverflow => write!(f, "heap overflow"),
+        }
+    }
+}
+
+/// A range of unmapped virtual memory that must never be accessed.
+#[derive(Debug, Clone, Copy)]
+pub struct GuardRegion {
+    /// The first address of the region.
+    pub start: VirtAddr,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// What the region protects against.
+    pub kind: GuardKind,
+    /// The name of the protected memory, e.g. the stack's owner.
+    pub name: &'static str,
+}
+
+impl GuardRegion {
+    /// Returns `true` if `addr` lies within the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr - self.start < self.size
+    }
+}
+
+impl fmt::Display for GuardRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} (guard {:#x}..{:#x})",
+            self.kind,
+            self.name,
+            self.start.as_u64(),
+            self.start.as_u64() + self.size
+        )
+    }
+}
+
+/// Register a guard region.
+///
+/// # Arguments
+/// * `start` - The first address of the region.
+/// * `size` - The size of the region in bytes.
+/// * `kind` - What the region protects against.
+/// * `name` - The name of the protected memory.
+///
+/// # Panics
+/// Panics if too many guard regions are registered.
+pub fn register(
+    start: VirtAddr,
+    size: u64,
+    kind: GuardKind,
+    name: &'static str,
+) {
+    let mut guards = GUARDS.lock();
+    let slot = guards
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many guard regions");
+    *slot = Some(GuardRegion {
+        start,
+        size,
+        kind,
+        name,
+    });
+}
+
+/// Unregister the guard region starting at `start`.
+pub fn unregister(start: VirtAddr) {
+    let mut guards = GUARDS.lock();
+    if let Some(slot) = guards
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|guard| guard.start == start))
+    {
+        *slot = None;
+    }
+}
+
+/// Returns the guard region containing `addr`, if any.
+///
+/// Never spins on the lock, so it can be used from exception handlers.
+/// Returns `None` if the guard list is currently locked.
+pub fn find(addr: VirtAddr) -> Option<GuardRegion> {
+    GUARDS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|guard| guard.contains(addr))
+        .copied()
+}
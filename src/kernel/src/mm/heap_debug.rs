@@ -0,0 +1,212 @@
+//! Checking heap mode
+//!
+//! Enabled with the `debug-heap` cargo feature. Every allocation is wrapped
+//! in redzones and a header recording its layout, and freed memory is
+//! poisoned:
+//!
+//! ```text
+//! | link | magic | size | align | redzone ... | payload | redzone |
+//! ```
+//!
+//! The first word of the block is left alone because the allocator links free
+//! blocks through it. Freeing checks the header and both redzones, which
+//! catches double frees, frees with the wrong layout and buffer overruns.
+//! Handing out a block from a size class again checks that its poison is
+//! intact, which catches writes after free. Every problem panics with a
+//! report naming the size class and the offending pointer.
+use core::{alloc::Layout, mem, ptr, slice};
+
+use x86_64::align_up;
+
+/// The byte freed memory is filled with.
+const POISON: u8 = 0x6b;
+
+/// The byte redzones are filled with.
+const REDZONE: u8 = 0xcc;
+
+/// The size of the redzones around the payload, not counting the header.
+const REDZONE_SIZE: usize = 16;
+
+/// The magic value marking a live allocation.
+const ALLOC_MAGIC: u64 = 0xa110_c8ed_a110_c8ed;
+
+/// The header at the start of every block.
+#[repr(C)]
+struct Header {
+    /// Reserved for the allocator's free list link.
+    _link: usize,
+    /// [`ALLOC_MAGIC`] while the block is allocated, poison once freed.
+    magic: u64,
+    /// The requested size of the allocation.
+    size: usize,
+    /// The requested alignment of the allocation.
+    align: usize,
+}
+
+/// Returns the distance between the start of a block and its payload.
+fn front_size(align: usize) -> usize {
+    align_up(
+        (mem::size_of::<Header>() + REDZONE_SIZE) as u64,
+        align as u64,
+    ) as usize
+}
+
+/// Returns the layout of the block holding an allocation with `layout`.
+pub(super) fn block_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        front_size(layout.align()) + layout.size() + REDZONE_SIZE,
+        layout.align(),
+    )
+    .expect("allocation too large for the debug heap")
+}
+
+/// Describes where a block came from, for reports.
+struct Origin(Option<usize>);
+
+impl core::fmt::Display for Origin {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(block_size) => write!(f, "{block_size} byte size class"),
+            None => write!(f, "fallback heap"),
+        }
+    }
+}
+
+/// Prepare a freshly allocated block and return the payload pointer.
+///
+/// Blocks taken from a size class are checked for writes after they were
+/// freed first.
+///
+/// # Safety
+/// `block` must point to a block of at least [`block_layout`]`(layout)`
+/// bytes, or of `block_size` bytes if it is taken from a size class.
+///
+/// # Arguments
+/// * `block` - The block returned by the allocator.
+/// * `layout` - The layout requested by the caller.
+/// * `block_size` - The block size of the size class the block is taken from,
+///   or `None` for the fallback heap.
+pub(super) unsafe fn on_alloc(
+    block: *mut u8,
+    layout: Layout,
+    block_size: Option<usize>,
+) -> *mut u8 {
+    if let Some(block_size) = block_size {
+        let contents = slice::from_raw_parts(block, block_size);
+        let link_size = mem::size_of::<usize>();
+        if let Some(offset) = contents[link_size..]
+            .iter()
+            .position(|&byte| byte != POISON)
+        {
+            panic!(
+                "heap corruption: freed block {:#x} in the {} was written at \
+                 {:#x} (offset {}) after it was freed",
+                block as usize,
+                Origin(Some(block_size)),
+                block as usize + link_size + offset,
+                link_size + offset
+            );
+        }
+    }
+
+    let front = front_size(layout.align());
+    let header = block as *mut Header;
+    (*header).magic = ALLOC_MAGIC;
+    (*header).size = layout.size();
+    (*header).align = layout.align();
+
+    let header_size = mem::size_of::<Header>();
+    ptr::write_bytes(block.add(header_size), REDZONE, front - header_size);
+    ptr::write_bytes(block.add(front + layout.size()), REDZONE, REDZONE_SIZE);
+    block.add(front)
+}
+
+/// Check an allocation that is about to be freed, poison it and return the
+/// block pointer.
+///
+/// # Safety
+/// `ptr` must have been returned by [`on_alloc`], or the checks are likely to
+/// read invalid memory (which is exactly what they try to report).
+///
+/// # Arguments
+/// * `ptr` - The pointer passed to `dealloc`.
+/// * `layout` - The layout passed to `dealloc`.
+/// * `block_size` - The block size of the size class the block belongs to, or
+///   `None` for the fallback heap.
+///
+/// # Panics
+/// Panics on a double free, a free with the wrong layout or a corrupted
+/// redzone.
+pub(super) unsafe fn on_dealloc(
+    ptr: *mut u8,
+    layout: Layout,
+    block_size: Option<usize>,
+) -> *mut u8 {
+    let origin = Origin(block_size);
+    let front = front_size(layout.align());
+    let block = ptr.sub(front);
+    let header = &*(block as *const Header);
+
+    if header.magic != ALLOC_MAGIC {
+        if header.magic == u64::from_ne_bytes([POISON; 8]) {
+            panic!(
+                "heap corruption: double free of {:#x} in the {}",
+                ptr as usize, origin
+            );
+        }
+        panic!(
+            "heap corruption: free of {:#x} in the {}, which is not a live \
+             allocation (magic {:#x})",
+            ptr as usize, origin, header.magic
+        );
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap corruption: {:#x} in the {} freed with size {} align {}, \
+             but allocated with size {} align {}",
+            ptr as usize,
+            origin,
+            layout.size(),
+            layout.align(),
+            header.size,
+            header.align
+        );
+    }
+
+    let header_size = mem::size_of::<Header>();
+    let front_redzone =
+        slice::from_raw_parts(block.add(header_size), front - header_size);
+    if let Some(offset) = front_redzone.iter().rposition(|&b| b != REDZONE) {
+        panic!(
+            "heap corruption: buffer underflow of {:#x} in the {}, {} bytes \
+             before the allocation were overwritten",
+            ptr as usize,
+            origin,
+            front_redzone.len() - offset
+        );
+    }
+    let back_redzone =
+        slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE);
+    if let Some(offset) = back_redzone.iter().position(|&b| b != REDZONE) {
+        panic!(
+            "heap corruption: buffer overflow of {:#x} in the {}, written {} \
+             bytes past the end of the {} byte allocation",
+            ptr as usize,
+            origin,
+            offset + 1,
+            layout.size()
+        );
+    }
+
+    let poisoned = block_size.unwrap_or(block_layout(layout).size());
+    ptr::write_bytes(block, POISON, poisoned);
+    block
+}
+
+/// Poison the blocks of a new slab.
+///
+/// # Safety
+/// `blocks` must be valid for writes of `size` bytes.
+pub(super) unsafe fn poison(blocks: *mut u8, size: usize) {
+    ptr::write_bytes(blocks, POISON, size);
+}
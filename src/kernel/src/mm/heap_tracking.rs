@@ -0,0 +1,172 @@
+//! Allocation call site tracking
+//!
+//! Enabled with the `heap-tracking` cargo feature. Every live heap allocation
+//! is recorded together with the return addresses of its callers and the tick
+//! it was made at, so that allocations which are never freed by long-running
+//! tasks can be traced back to their call site.
+//!
+//! The tracker lives in a fixed-size table and never allocates itself.
+use super::Locked;
+use crate::{debug::backtrace, devices::timer::get_ticks};
+
+/// The maximum number of live allocations that can be tracked.
+const MAX_TRACKED: usize = 1024;
+
+/// The number of return addresses recorded per allocation.
+pub const CALL_SITE_DEPTH: usize = 4;
+
+/// The number of frames belonging to the allocator itself, which are skipped
+/// when recording the call site.
+const SKIPPED_FRAMES: usize = 3;
+
+static TRACKER: Locked<Tracker> = Locked::new(Tracker::new());
+
+/// A live allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackedAllocation {
+    /// The address of the allocation.
+    pub ptr: usize,
+    /// The size of the allocation in bytes.
+    pub size: usize,
+    /// The tick the allocation was made at.
+    pub ticks: u64,
+    /// Return addresses of the allocating code, innermost first.
+    pub call_site: [usize; CALL_SITE_DEPTH],
+}
+
+/// The table of live allocations.
+struct Tracker {
+    /// The live allocations.
+    slots: [Option<TrackedAllocation>; MAX_TRACKED],
+    /// The number of allocations that could not be recorded because the
+    /// table was full.
+    dropped: u64,
+}
+
+impl Tracker {
+    /// Creates an empty Tracker.
+    const fn new() -> Self {
+        Tracker {
+            slots: [None; MAX_TRACKED],
+            dropped: 0,
+        }
+    }
+}
+
+/// Record a new allocation.
+///
+/// Called by the global allocator after a successful allocation.
+#[inline(never)]
+pub(super) fn record_alloc(ptr: *mut u8, size: usize) {
+    let allocation = TrackedAllocation {
+        ptr: ptr as usize,
+        size,
+        ticks: get_ticks(),
+        call_site: call_site(),
+    };
+
+    let mut tracker = TRACKER.lock();
+    match tracker.slots.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(allocation),
+        None => tracker.dropped += 1,
+    }
+}
+
+/// Forget a freed allocation.
+///
+/// Called by the global allocator before the memory is released.
+pub(super) fn record_dealloc(ptr: *mut u8) {
+    let mut tracker = TRACKER.lock();
+    if let Some(slot) = tracker
+        .slots
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|a| a.ptr == ptr as usize))
+    {
+        *slot = None;
+    }
+}
+
+/// Call `f` for every live allocation that is at least `min_age` ticks old.
+pub fn for_each_live_allocation(
+    min_age: u64,
+    f: impl FnMut(&TrackedAllocation),
+) {
+    let now = get_ticks();
+    let tracker = TRACKER.lock();
+    tracker
+        .slots
+        .iter()
+        .flatten()
+        .filter(|allocation| now - allocation.ticks >= min_age)
+        .for_each(f);
+}
+
+/// Log the live allocations that are at least `min_age` ticks old, grouped by
+/// call site.
+///
+/// Allocations that stay alive for a long time and keep growing in number
+/// for the same call site are likely leaks.
+///
+/// # Arguments
+/// * `min_age` - The minimum age in ticks of the allocations to log.
+/// * `level` - The log level to use.
+pub fn log_live_allocations(min_age: u64, level: log::Level) {
+    let now = get_ticks();
+    let tracker = TRACKER.lock();
+    let live = || {
+        tracker
+            .slots
+            .iter()
+            .flatten()
+            .filter(|allocation| now - allocation.ticks >= min_age)
+    };
+
+    log::log!(
+        level,
+        "Live allocations older than {} ticks ({} untracked):",
+        min_age,
+        tracker.dropped
+    );
+    for (index, allocation) in live().enumerate() {
+        // only report each call site once, at its first occurrence
+        if live()
+            .take(index)
+            .any(|other| other.call_site == allocation.call_site)
+        {
+            continue;
+        }
+
+        let (count, bytes) = live()
+            .filter(|other| other.call_site == allocation.call_site)
+            .fold((0, 0), |(count, bytes), other| {
+                (count + 1, bytes + other.size)
+            });
+        log::log!(
+            level,
+            "  {} allocations, {} bytes from {:x?}",
+            count,
+            bytes,
+            allocation.call_site
+        );
+    }
+}
+
+/// Collect the return addresses of the callers of the global allocator with
+/// [`backtrace::walk`].
+///
+/// Requires the kernel to be built with frame pointers.
+#[inline(always)]
+fn call_site() -> [usize; CALL_SITE_DEPTH] {
+    let mut call_site = [0; CALL_SITE_DEPTH];
+    let mut depth: usize = 0;
+    backtrace::walk(backtrace::frame_pointer(), |return_address| {
+        if let Some(slot) = depth
+            .checked_sub(SKIPPED_FRAMES)
+            .and_then(|index| call_site.get_mut(index))
+        {
+            *slot = return_address as usize;
+        }
+        depth += 1;
+    });
+    call_site
+}
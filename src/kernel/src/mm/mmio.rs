@@ -0,0 +1,313 @@
+//! Memory mapped I/O
+//!
+//! Maps device memory into the kernel virtual region (see [`super::vmm`]) and
+//! hands out [`Mmio`] handles for volatile register access. Large blocks are
+//! mapped with huge pages where possible. The mapping is removed again when
+//! the handle is dropped.
+use core::{fmt, mem};
+
+use x86_64::{
+    align_down, align_up,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError},
+        Mapper, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging, pat, vmm};
+
+/// The caching behaviour of a memory mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Normal cached memory.
+    WriteBack,
+    /// Reads are cached, writes go straight to memory.
+    WriteThrough,
+    /// Reads are not cached, writes are collected in a buffer and written out
+    /// in bursts. Suited for framebuffers. Falls back to [`Self::Uncached`]
+    /// if the PAT is not available.
+    WriteCombining,
+    /// No caching at all, required for most device registers.
+    Uncached,
+}
+
+impl CachePolicy {
+    /// Returns the page table flags selecting this cache policy.
+    ///
+    /// The flags depend on whether the PAT has been programmed, see
+    /// [`super::pat`].
+    pub fn flags(self) -> PageTableFlags {
+        let pat = pat::is_enabled();
+        match self {
+            CachePolicy::WriteBack => PageTableFlags::empty(),
+            CachePolicy::WriteThrough if pat => PageTableFlags::NO_CACHE,
+            CachePolicy::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::WriteCombining if pat => PageTableFlags::WRITE_THROUGH,
+            CachePolicy::WriteCombining | CachePolicy::Uncached => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+
+    /// Returns the cache policy selected by page table flags.
+    pub fn from_flags(flags: PageTableFlags) -> Self {
+        let pat = pat::is_enabled();
+        match (
+            flags.contains(PageTableFlags::NO_CACHE),
+            flags.contains(PageTableFlags::WRITE_THROUGH),
+        ) {
+            (false, false) => CachePolicy::WriteBack,
+            (false, true) if pat => CachePolicy::WriteCombining,
+            (false, true) => CachePolicy::WriteThrough,
+            (true, false) if pat => CachePolicy::WriteThrough,
+            (true, _) => CachePolicy::Uncached,
+        }
+    }
+}
+
+/// An error that occurred while mapping device memory.
+#[derive(Debug)]
+pub enum MmioError {
+    /// The kernel virtual region is exhausted.
+    OutOfVirtualMemory,
+    /// Mapping a page failed.
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        MmioError::Map(error)
+    }
+}
+
+/// A mapped block of device memory.
+///
+/// All accesses are volatile and bounds checked. Dropping the handle unmaps
+/// the memory.
+pub struct Mmio {
+    /// The virtual address of the first byte of the block.
+    base: VirtAddr,
+    /// The physical address of the first byte of the block.
+    phys: PhysAddr,
+    /// The size of the block in bytes.
+    size: usize,
+    /// The start of the virtual range reserved for the block, which may lie
+    /// below the first mapped page to align the mapping for huge pages.
+    range_start: VirtAddr,
+}
+
+impl Mmio {
+    /// Returns the virtual address of the block.
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// Returns the physical address of the block.
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Returns the size of the block in bytes.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the block is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns a pointer to the register at `offset`.
+    ///
+    /// # Panics
+    /// Panics if the register is out of bounds or misaligned.
+    pub fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + mem::size_of::<T>() <= self.size,
+            "MMIO access at {offset:#x} out of bounds"
+        );
+        let ptr = (self.base + offset as u64).as_mut_ptr::<T>();
+        assert!(ptr.is_aligned(), "misaligned MMIO access at {offset:#x}");
+        ptr
+    }
+
+    /// Read the register at `offset`.
+    ///
+    /// # Panics
+    /// Panics if the register is out of bounds or misaligned.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Write `value` to the register at `offset`.
+    ///
+    /// # Panics
+    /// Panics if the register is out of bounds or misaligned.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    /// Returns the start of the first page backing the block.
+    fn mapping_start(&self) -> VirtAddr {
+        self.base.align_down(Size4KiB::SIZE)
+    }
+
+    /// Returns the size of the pages backing the block.
+    fn mapping_size(&self) -> u64 {
+        align_up(
+            self.base - self.mapping_start() + self.size as u64,
+            Size4KiB::SIZE,
+        )
+    }
+}
+
+impl fmt::Debug for Mmio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mmio")
+            .field("base", &self.base)
+            .field("phys", &self.phys)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        let mut mapper = paging::mapper();
+        paging::unmap_range(
+            &mut *mapper,
+            self.mapping_start(),
+            self.mapping_size(),
+        );
+        // the tables were allocated by `map_range` in `map_mmio`
+        unsafe {
+            paging::free_empty_tables(
+                &mut mapper,
+                self.mapping_start(),
+                self.mapping_size(),
+                &mut *FRAME_ALLOCATOR.lock(),
+            )
+        };
+        drop(mapper);
+
+        let range_size =
+            self.mapping_start() - self.range_start + self.mapping_size();
+        vmm::deallocate(self.range_start, range_size);
+    }
+}
+
+/// Map a block of device memory into the kernel virtual region.
+///
+/// # Safety
+/// The caller must guarantee that `[phys, phys + size)` is device memory (or
+/// otherwise not used as normal RAM) and that accessing it has no unwanted
+/// side effects.
+///
+/// # Arguments
+/// * `phys` - The physical address of the block. Does not need to be page
+///   aligned.
+/// * `size` - The size of the block in bytes.
+/// * `cache_policy` - The caching behaviour of the mapping.
+pub unsafe fn map_mmio(
+    phys: PhysAddr,
+    size: usize,
+    cache_policy: CachePolicy,
+) -> Result<Mmio, MmioError> {
+    assert!(size > 0, "cannot map an empty MMIO block");
+
+    let phys_start = align_down(phys.as_u64(), Size4KiB::SIZE);
+    let offset = phys.as_u64() - phys_start;
+    let mapping_size = align_up(offset + size as u64, Size4KiB::SIZE);
+
+    // give the virtual range the same offset into a huge page as the
+    // physical one, so large blocks can be mapped with huge pages
+    let page_size = paging::largest_page_size(mapping_size);
+    let huge_offset = phys_start % page_size;
+    let range_start = vmm::allocate(huge_offset + mapping_size, page_size)
+        .ok_or(MmioError::OutOfVirtualMemory)?;
+    let mmio = Mmio {
+        base: range_start + huge_offset + offset,
+        phys,
+        size,
+        range_start,
+    };
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache_policy.flags();
+    let mut mapper = paging::mapper();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    // on failure, dropping the handle unmaps what was mapped so far
+    let result = paging::map_range(
+        &mut *mapper,
+        mmio.mapping_start(),
+        PhysAddr::new(phys_start),
+        mapping_size,
+        flags,
+        &mut *frame_allocator,
+    );
+    drop(frame_allocator);
+    drop(mapper);
+    result?;
+
+    Ok(mmio)
+}
+
+/// Change the cache policy of an existing mapping in the kernel's page table.
+///
+/// Used for memory the kernel did not map itself, like the framebuffer set up
+/// by the bootloader. Huge pages overlapping the range are changed as a
+/// whole.
+///
+/// # Safety
+/// The caller must guarantee that the new cache policy is valid for the
+/// memory behind the range, e.g. that normal RAM is not mapped with a policy
+/// that conflicts with other mappings of it.
+///
+/// # Arguments
+/// * `start` - The first address of the range.
+/// * `size` - The size of the range in bytes.
+/// * `cache_policy` - The new caching behaviour.
+pub unsafe fn set_cache_policy(
+    start: VirtAddr,
+    size: usize,
+    cache_policy: CachePolicy,
+) -> Result<(), FlagUpdateError> {
+    let cache_flags = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let end = start + size as u64;
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    let mut mapper = paging::mapper();
+
+    while addr < end {
+        let walk = paging::walk(addr);
+        let step = match walk.last_step() {
+            Some(step) if walk.is_mapped() => *step,
+            _ => return Err(FlagUpdateError::PageNotMapped),
+        };
+        let flags = (step.flags - cache_flags) | cache_policy.flags();
+        match step.level {
+            1 => Mapper::<Size4KiB>::update_flags(
+                &mut *mapper,
+                Page::containing_address(addr),
+                flags,
+            )?
+            .flush(),
+            2 => Mapper::<Size2MiB>::update_flags(
+                &mut *mapper,
+                Page::containing_address(addr),
+                flags,
+            )?
+            .flush(),
+            _ => Mapper::<Size1GiB>::update_flags(
+                &mut *mapper,
+                Page::containing_address(addr),
+                flags,
+            )?
+            .flush(),
+        }
+        addr = addr.align_down(walk.region_size()) + walk.region_size();
+    }
+    Ok(())
+}
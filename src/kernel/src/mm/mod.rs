@@ -1,8 +1,26 @@
 //! Memory Management module.
+pub mod address_space;
 pub mod allocator;
+pub mod boot_memory;
+pub mod demand_paging;
+pub mod dma;
+pub mod frame_allocator;
+pub mod guard;
+#[cfg(feature = "debug-heap")]
+mod heap_debug;
+#[cfg(feature = "heap-tracking")]
+pub mod heap_tracking;
+pub mod mmio;
 pub mod paging;
+pub mod pat;
+pub mod stack;
+pub mod vmm;
 
 /// A simple wrapper around spin::Mutex to provide a locked value.
+///
+/// Must not be taken in interrupt handlers, which debug builds assert. State
+/// shared with interrupt handlers belongs in an
+/// [`IrqSafeMutex`](crate::sync::IrqSafeMutex).
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
@@ -16,7 +34,18 @@ impl<A> Locked<A> {
     }
 
     /// Lock the value and return a MutexGuard.
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        debug_assert!(
+            !crate::sync::in_interrupt(),
+            "plain lock taken in interrupt context"
+        );
         self.inner.lock()
     }
+
+    /// Try to lock the value without spinning.
+    ///
+    /// Returns `None` if the value is already locked.
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
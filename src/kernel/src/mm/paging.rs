@@ -1,65 +1,847 @@
 //! Paging module
-use bootloader_api::info::{MemoryRegionKind::Usable, MemoryRegions};
+use core::{arch::x86_64::__cpuid, fmt};
+
+use spin::{MutexGuard, Once};
 use x86_64::{
+    align_down,
+    instructions::tlb,
     registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+        mapper::{MapToError, TranslateResult},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PageTableIndex, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory
-/// map.
-pub struct BootInfoFrameAllocator {
-    /// The memory regions from the bootloader.
-    memory_map: &'static MemoryRegions,
-    /// The next frame to return.
-    next: usize,
+use super::{mmio::CachePolicy, Locked};
+
+/// The first address of the upper half, which is shared by all address
+/// spaces and belongs to the kernel.
+pub const KERNEL_HALF_START: u64 = 0xffff_8000_0000_0000;
+
+/// The index of the first level 4 entry of the kernel half.
+pub const KERNEL_HALF_FIRST_ENTRY: usize = 256;
+
+/// The start of the range the bootloader places its dynamic mappings in (the
+/// kernel image, boot stack, boot info, physical memory mapping and
+/// framebuffer). Must be passed to the bootloader config.
+pub const BOOTLOADER_DYNAMIC_START: u64 = KERNEL_HALF_START;
+
+/// The end of the range the bootloader places its dynamic mappings in. The
+/// kernel heap and the kernel virtual region lie above it.
+pub const BOOTLOADER_DYNAMIC_END: u64 = 0xffff_c000_0000_0000;
+
+/// The kernel's offset page table, set up by [`init`].
+static MAPPER: Once<Locked<OffsetPageTable<'static>>> = Once::new();
+
+/// The virtual address all physical memory is mapped at, set up by [`init`].
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The frame of the kernel's level 4 table, set up by [`init`].
+static KERNEL_LEVEL_4_FRAME: Once<PhysFrame> = Once::new();
+
+/// Initialize the offset page table.
+///
+/// Must be called before [`mapper`] is used.
+///
+/// # Arguments
+/// * `physical_memory_offset` - The offset of the physical memory.
+pub fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    KERNEL_LEVEL_4_FRAME.call_once(|| Cr3::read().0);
+    MAPPER.call_once(|| {
+        let level4_table = active_level4_table(physical_memory_offset);
+        let mapper = unsafe {
+            OffsetPageTable::new(level4_table, physical_memory_offset)
+        };
+        Locked::new(mapper)
+    });
+    log::info!("1 GiB pages supported: {}", supports_1gib_pages());
+}
+
+/// Lock the kernel's offset page table.
+///
+/// # Panics
+/// Panics if [`init`] has not been called yet.
+pub fn mapper() -> MutexGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.get().expect("paging not initialized").lock()
+}
+
+/// Try to lock the kernel's offset page table without spinning.
+///
+/// Returns `None` if paging is not initialized or the page table is already
+/// locked, e.g. because the caller interrupted code that is modifying it.
+pub fn try_mapper() -> Option<MutexGuard<'static, OffsetPageTable<'static>>> {
+    MAPPER.get()?.try_lock()
+}
+
+/// Returns the virtual address all physical memory is mapped at.
+///
+/// # Panics
+/// Panics if [`init`] has not been called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("paging not initialized")
+}
+
+/// Returns the frame of the kernel's level 4 table.
+///
+/// # Panics
+/// Panics if [`init`] has not been called yet.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME.get().expect("paging not initialized")
+}
+
+/// A single entry visited during a page table walk.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    /// The level of the table the entry belongs to, from 4 down to 1.
+    pub level: u8,
+    /// The index of the entry in its table.
+    pub index: PageTableIndex,
+    /// The flags of the entry.
+    pub flags: PageTableFlags,
+    /// The physical address the entry points to.
+    pub addr: PhysAddr,
+}
+
+/// The result of walking the active page table for a virtual address.
+#[derive(Debug, Clone, Copy)]
+pub struct PageWalk {
+    /// The address that was looked up.
+    pub addr: VirtAddr,
+    /// The entries visited, starting at the level 4 table.
+    pub steps: [Option<WalkStep>; 4],
+}
+
+impl PageWalk {
+    /// Returns the last entry visited by the walk.
+    pub fn last_step(&self) -> Option<&WalkStep> {
+        self.steps.iter().flatten().last()
+    }
+
+    /// Returns `true` if the address is mapped.
+    pub fn is_mapped(&self) -> bool {
+        self.last_step().is_some_and(|step| {
+            step.flags.contains(PageTableFlags::PRESENT)
+                && (step.level == 1
+                    || step.flags.contains(PageTableFlags::HUGE_PAGE))
+        })
+    }
+
+    /// Returns the size of the page mapping the address, or the size of the
+    /// region covered by the first non-present entry.
+    pub fn region_size(&self) -> u64 {
+        self.last_step()
+            .map_or(level_size(4), |step| level_size(step.level))
+    }
+
+    /// Returns the physical address the address is mapped to.
+    pub fn translate(&self) -> Option<PhysAddr> {
+        if !self.is_mapped() {
+            return None;
+        }
+        let step = self.last_step()?;
+        Some(step.addr + (self.addr.as_u64() & (level_size(step.level) - 1)))
+    }
+
+    /// Returns the flags that are in effect for the address.
+    ///
+    /// An access is only writable or user accessible if every level allows
+    /// it, and is not executable if any level forbids it. Returns `None` if
+    /// the address is not mapped.
+    pub fn effective_flags(&self) -> Option<PageTableFlags> {
+        if !self.is_mapped() {
+            return None;
+        }
+        Some(
+            self.steps
+                .iter()
+                .flatten()
+                .fold(TOP_LEVEL_FLAGS, |parent, step| {
+                    effective_flags(parent, step.flags)
+                }),
+        )
+    }
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a new BootInfoFrameAllocator.
-    pub fn new(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page table walk for {:#x}:", self.addr.as_u64())?;
+        for step in self.steps.iter().flatten() {
+            write!(
+                f,
+                "\n  L{}[{:>3}] -> {:#x} {:?}",
+                step.level,
+                u16::from(step.index),
+                step.addr.as_u64(),
+                step.flags
+            )?;
+        }
+        match (self.translate(), self.effective_flags()) {
+            (Some(phys), Some(flags)) => {
+                write!(f, "\n  => {:#x} {}", phys.as_u64(), FlagSummary(flags))
+            }
+            _ => write!(f, "\n  => not mapped"),
         }
     }
+}
 
-    /// Returns an iterator over the usable frames from the memory map.
-    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
+/// Walk the active page table for `addr`.
+///
+/// Reads the tables through the physical memory mapping without taking any
+/// locks, so it is safe to use from exception handlers and the panic path.
+///
+/// # Arguments
+/// * `addr` - The virtual address to look up.
+pub fn walk(addr: VirtAddr) -> PageWalk {
+    let mut walk = PageWalk {
+        addr,
+        steps: [None; 4],
+    };
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.get() else {
+        return walk;
+    };
 
-        let usable_regions = regions.filter(|region| region.kind == Usable);
-        let address_ranges =
-            usable_regions.map(|region| region.start..region.end);
-        let frame_addresses =
-            address_ranges.flat_map(|region| region.step_by(4096));
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table_addr = Cr3::read().0.start_address();
+    for (step, index) in indices.into_iter().enumerate() {
+        let level = 4 - step as u8;
+        let table: &PageTable =
+            unsafe { &*(offset + table_addr.as_u64()).as_ptr() };
+        let entry = &table[index];
+        walk.steps[step] = Some(WalkStep {
+            level,
+            index,
+            flags: entry.flags(),
+            addr: entry.addr(),
+        });
 
-        frame_addresses.map(|address| {
-            PhysFrame::containing_address(PhysAddr::new(address))
-        })
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            break;
+        }
+        table_addr = entry.addr();
     }
+    walk
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// A virtually and physically contiguous range mapped with the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    /// The first virtual address of the range.
+    pub virt: VirtAddr,
+    /// The physical address the range is mapped to.
+    pub phys: PhysAddr,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The flags in effect for the range, see
+    /// [`PageWalk::effective_flags`].
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    /// Returns `true` if `other` directly follows this range and can be
+    /// merged into it.
+    fn continues_with(&self, other: &MappedRange) -> bool {
+        self.flags == other.flags
+            && self.virt.as_u64().checked_add(self.size)
+                == Some(other.virt.as_u64())
+            && self.phys + self.size == other.phys
     }
 }
 
-/// Initialize the offset page table.
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>8} KiB {}",
+            self.virt.as_u64(),
+            self.virt.as_u64() + (self.size - 1),
+            self.phys.as_u64(),
+            self.size / 1024,
+            FlagSummary(self.flags)
+        )
+    }
+}
+
+/// Compact formatting of the permission and caching bits of page table
+/// flags, e.g. `rw- k g wc`.
+struct FlagSummary(PageTableFlags);
+
+impl fmt::Display for FlagSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = self.0;
+        let bit =
+            |flag, set, unset| if flags.contains(flag) { set } else { unset };
+        write!(
+            f,
+            "r{}{} {}",
+            bit(PageTableFlags::WRITABLE, "w", "-"),
+            if flags.contains(PageTableFlags::NO_EXECUTE) {
+                "-"
+            } else {
+                "x"
+            },
+            bit(PageTableFlags::USER_ACCESSIBLE, "u", "k"),
+        )?;
+        if flags.contains(PageTableFlags::GLOBAL) {
+            write!(f, " g")?;
+        }
+        match CachePolicy::from_flags(flags) {
+            CachePolicy::WriteBack => Ok(()),
+            CachePolicy::WriteThrough => write!(f, " wt"),
+            CachePolicy::WriteCombining => write!(f, " wc"),
+            CachePolicy::Uncached => write!(f, " uc"),
+        }
+    }
+}
+
+/// The flags a walk starts with before any entry restricts them.
+const TOP_LEVEL_FLAGS: PageTableFlags =
+    PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Combine the effective flags of a parent entry with the flags of a child.
+///
+/// The bits the CPU updates on access and the huge page bit are dropped, so
+/// that ranges only differing in those can be coalesced.
+fn effective_flags(
+    parent: PageTableFlags,
+    flags: PageTableFlags,
+) -> PageTableFlags {
+    let mut effective = flags
+        - (PageTableFlags::ACCESSED
+            | PageTableFlags::DIRTY
+            | PageTableFlags::HUGE_PAGE);
+    effective.set(
+        PageTableFlags::WRITABLE,
+        parent.contains(PageTableFlags::WRITABLE)
+            && flags.contains(PageTableFlags::WRITABLE),
+    );
+    effective.set(
+        PageTableFlags::USER_ACCESSIBLE,
+        parent.contains(PageTableFlags::USER_ACCESSIBLE)
+            && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+    );
+    effective.set(
+        PageTableFlags::NO_EXECUTE,
+        parent.contains(PageTableFlags::NO_EXECUTE)
+            || flags.contains(PageTableFlags::NO_EXECUTE),
+    );
+    effective
+}
+
+/// Call `f` for every present mapping of the active page table, with
+/// adjacent pages coalesced into ranges.
+///
+/// Reads the tables through the physical memory mapping without taking any
+/// locks or allocating, so it is safe to use from the panic path.
+pub fn for_each_mapping(mut f: impl FnMut(&MappedRange)) {
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.get() else {
+        return;
+    };
+
+    let mut current: Option<MappedRange> = None;
+    let mut visit = |range: MappedRange| match current.as_mut() {
+        Some(current) if current.continues_with(&range) => {
+            current.size += range.size;
+        }
+        _ => {
+            if let Some(previous) = current.replace(range) {
+                f(&previous);
+            }
+        }
+    };
+    visit_table(
+        offset,
+        Cr3::read().0.start_address(),
+        4,
+        0,
+        TOP_LEVEL_FLAGS,
+        &mut visit,
+    );
+    if let Some(last) = current {
+        f(&last);
+    }
+}
+
+/// Visit the present leaf entries reachable from a page table.
 ///
 /// # Arguments
-/// * `physical_memory_offset` - The offset of the physical memory.
+/// * `offset` - The virtual address physical memory is mapped at.
+/// * `table_addr` - The physical address of the table.
+/// * `level` - The level of the table, from 4 down to 1.
+/// * `base` - The first virtual address covered by the table.
+/// * `parent` - The effective flags of the entries leading to the table.
+/// * `visit` - Called for every present page.
+fn visit_table(
+    offset: VirtAddr,
+    table_addr: PhysAddr,
+    level: u8,
+    base: u64,
+    parent: PageTableFlags,
+    visit: &mut dyn FnMut(MappedRange),
+) {
+    let table: &PageTable =
+        unsafe { &*(offset + table_addr.as_u64()).as_ptr() };
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let addr = base + index as u64 * level_size(level);
+        let effective = effective_flags(parent, flags);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            visit(MappedRange {
+                virt: VirtAddr::new_truncate(addr),
+                phys: entry.addr(),
+                size: level_size(level),
+                flags: effective,
+            });
+        } else {
+            visit_table(
+                offset,
+                entry.addr(),
+                level - 1,
+                addr,
+                effective,
+                visit,
+            );
+        }
+    }
+}
+
+/// Log every present mapping of the active page table as coalesced ranges.
+///
+/// Safe to use from the panic path, see [`for_each_mapping`].
+///
+/// # Arguments
+/// * `level` - The log level to use.
+pub fn log_mappings(level: log::Level) {
+    log::log!(
+        level,
+        "Mappings of page table at {:#x}:",
+        Cr3::read().0.start_address().as_u64()
+    );
+    let mut ranges = 0;
+    for_each_mapping(|range| {
+        ranges += 1;
+        log::log!(level, "  {range}");
+    });
+    log::log!(level, "{ranges} ranges");
+}
+
+/// Log how `addr` is translated by the active page table.
+///
+/// Safe to use from the panic path, see [`walk`].
+///
+/// # Arguments
+/// * `addr` - The virtual address to translate.
+/// * `level` - The log level to use.
+pub fn log_translation(addr: VirtAddr, level: log::Level) {
+    log::log!(level, "{}", walk(addr));
+}
+
+/// Call `f` for the frame of every page table reachable from the active level
+/// 4 table, including the level 4 table itself.
+///
+/// Reads the tables through the physical memory mapping without taking any
+/// locks.
+pub fn for_each_page_table(mut f: impl FnMut(PhysFrame)) {
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.get() else {
+        return;
+    };
+    visit_tables(offset, Cr3::read().0, 4, &mut f);
+}
+
+/// Visit a page table and all of its child tables.
+fn visit_tables(
+    offset: VirtAddr,
+    frame: PhysFrame,
+    level: u8,
+    f: &mut dyn FnMut(PhysFrame),
+) {
+    f(frame);
+    if level == 1 {
+        return;
+    }
+
+    let table: &PageTable =
+        unsafe { &*(offset + frame.start_address().as_u64()).as_ptr() };
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT)
+            && !flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            let child = PhysFrame::containing_address(entry.addr());
+            visit_tables(offset, child, level - 1, f);
+        }
+    }
+}
+
+/// Find the closest mapped pages below and above `addr`.
+///
+/// Non-present upper level entries are skipped as a whole, so large holes are
+/// cheap to search.
+///
+/// # Arguments
+/// * `addr` - The virtual address to search around.
+/// * `max_distance` - How far to search in each direction.
+///
+/// # Returns
+/// The start addresses of the nearest mapped page below and above `addr`.
+pub fn nearest_mappings(
+    addr: VirtAddr,
+    max_distance: u64,
+) -> (Option<VirtAddr>, Option<VirtAddr>) {
+    let addr = addr.as_u64();
+
+    let mut below = None;
+    let mut current = addr;
+    let lower_limit = addr.saturating_sub(max_distance);
+    while let Ok(virt) = VirtAddr::try_new(current) {
+        let walk = walk(virt);
+        let region_start = align_down(current, walk.region_size());
+        if walk.is_mapped() {
+            below = Some(VirtAddr::new(region_start));
+            break;
+        }
+        match region_start.checked_sub(1) {
+            Some(previous) if previous >= lower_limit => current = previous,
+            _ => break,
+        }
+    }
+
+    let mut above = None;
+    let mut current = addr;
+    let upper_limit = addr.saturating_add(max_distance);
+    while let Ok(virt) = VirtAddr::try_new(current) {
+        let walk = walk(virt);
+        let region_start = align_down(current, walk.region_size());
+        if walk.is_mapped() && current != addr {
+            above = Some(VirtAddr::new(region_start));
+            break;
+        }
+        match region_start.checked_add(walk.region_size()) {
+            Some(next) if next <= upper_limit => current = next,
+            _ => break,
+        }
+    }
+
+    (below, above)
+}
+
+/// Returns `true` if the CPU supports 1 GiB pages.
+///
+/// Checks the `Page1GB` bit of CPUID leaf `0x8000_0001`. The result is cached.
+pub fn supports_1gib_pages() -> bool {
+    static SUPPORTED: Once<bool> = Once::new();
+
+    *SUPPORTED.call_once(|| {
+        let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+        max_extended_leaf >= 0x8000_0001
+            && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+    })
+}
+
+/// Returns the largest page size usable for a region of `size` bytes.
+///
+/// 1 GiB pages are only returned if the CPU supports them.
+pub fn largest_page_size(size: u64) -> u64 {
+    if size >= Size1GiB::SIZE && supports_1gib_pages() {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Returns the size of the largest page that can map `virt` to `phys` with
+/// at most `remaining` bytes.
+fn page_size_for(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> u64 {
+    [Size1GiB::SIZE, Size2MiB::SIZE]
+        .into_iter()
+        .filter(|&size| size <= largest_page_size(remaining))
+        .find(|&size| virt.is_aligned(size) && phys.is_aligned(size))
+        .unwrap_or(Size4KiB::SIZE)
+}
+
+/// Convert a mapping error for a huge page into the error for a 4 KiB page.
+fn narrow_map_error<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        ),
+    }
+}
+
+/// Map a physically contiguous range using the largest pages possible.
+///
+/// Wherever `virt` and `phys` are both aligned to 2 MiB (or 1 GiB, if
+/// supported) and enough of the range remains, a huge page is used, which
+/// saves page table frames and TLB entries. The rest is mapped with 4 KiB
+/// pages.
+///
+/// # Safety
+/// The caller must guarantee that the physical range may be mapped with the
+/// given flags and that the virtual range is unused.
+///
+/// # Arguments
+/// * `mapper` - The page table to map into.
+/// * `virt` - The page aligned start of the virtual range.
+/// * `phys` - The page aligned start of the physical range.
+/// * `size` - The size of the range in bytes, a multiple of 4 KiB.
+/// * `flags` - The flags of the mapping.
+/// * `frame_allocator` - The allocator for new page tables.
 ///
 /// # Returns
-/// A static offset page table.
-pub fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level4_table = active_level4_table(physical_memory_offset);
-    unsafe { OffsetPageTable::new(level4_table, physical_memory_offset) }
+/// On failure, the pages mapped so far are left in place. Use
+/// [`unmap_range`] to remove them.
+pub unsafe fn map_range<M, A>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys) = (virt + offset, phys + offset);
+        let page_size = page_size_for(virt, phys, size - offset);
+        match page_size {
+            Size1GiB::SIZE => map_page::<Size1GiB, _, _>(
+                mapper,
+                virt,
+                phys,
+                flags,
+                frame_allocator,
+            )?,
+            Size2MiB::SIZE => map_page::<Size2MiB, _, _>(
+                mapper,
+                virt,
+                phys,
+                flags,
+                frame_allocator,
+            )?,
+            _ => map_page::<Size4KiB, _, _>(
+                mapper,
+                virt,
+                phys,
+                flags,
+                frame_allocator,
+            )?,
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Map a single page of size `S`.
+unsafe fn map_page<S, M, A>(
+    mapper: &mut M,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    S: PageSize,
+    M: Mapper<S>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+    mapper
+        .map_to(page, frame, flags, frame_allocator)
+        .map_err(narrow_map_error)?
+        .flush();
+    Ok(())
+}
+
+/// Unmap a range mapped with pages of any size.
+///
+/// Pages in the range that are not mapped are skipped. The frames backing
+/// the range are not freed, and neither are the page tables left empty, see
+/// [`free_empty_tables`].
+///
+/// # Arguments
+/// * `mapper` - The page table to unmap from.
+/// * `virt` - The page aligned start of the virtual range.
+/// * `size` - The size of the range in bytes, a multiple of 4 KiB.
+///
+/// # Panics
+/// Panics if a huge page only partially overlaps the range.
+pub fn unmap_range<M>(mapper: &mut M, virt: VirtAddr, size: u64)
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate,
+{
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt + offset;
+        let page_size = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => frame.size(),
+            // nothing mapped here, skip the 4 KiB page
+            _ => {
+                offset += Size4KiB::SIZE;
+                continue;
+            }
+        };
+        assert!(
+            addr.is_aligned(page_size) && offset + page_size <= size,
+            "huge page at {:#x} only partially unmapped",
+            addr.as_u64()
+        );
+        let result = match page_size {
+            Size1GiB::SIZE => Mapper::<Size1GiB>::unmap(
+                mapper,
+                Page::containing_address(addr),
+            )
+            .map(|(_, flush)| flush.flush()),
+            Size2MiB::SIZE => Mapper::<Size2MiB>::unmap(
+                mapper,
+                Page::containing_address(addr),
+            )
+            .map(|(_, flush)| flush.flush()),
+            _ => Mapper::<Size4KiB>::unmap(
+                mapper,
+                Page::containing_address(addr),
+            )
+            .map(|(_, flush)| flush.flush()),
+        };
+        if let Err(error) = result {
+            panic!("failed to unmap {:#x}: {:?}", addr.as_u64(), error);
+        }
+        offset += page_size;
+    }
+}
+
+/// Free the level 1 and level 2 tables covering a kernel range that no longer
+/// map anything, e.g. after [`unmap_range`].
+///
+/// Level 3 tables are kept: every address space shares them through its copy
+/// of the kernel's level 4 entries.
+///
+/// # Safety
+/// The tables covering the range must have been allocated from
+/// `frame_deallocator`, which holds for the kernel virtual region (see
+/// [`super::vmm`]) but not for the bootloader's mappings.
+///
+/// # Arguments
+/// * `mapper` - The kernel page table.
+/// * `virt` - The start of the virtual range.
+/// * `size` - The size of the range in bytes.
+/// * `frame_deallocator` - The allocator to return the table frames to.
+pub unsafe fn free_empty_tables<A>(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    size: u64,
+    frame_deallocator: &mut A,
+) where
+    A: FrameDeallocator<Size4KiB>,
+{
+    if size == 0 {
+        return;
+    }
+    let offset = mapper.phys_offset();
+    let level_4 = mapper.level_4_table_mut();
+    let last = virt + (size - 1);
+    let mut addr = virt.align_down(Size2MiB::SIZE);
+    while addr <= last {
+        let next = addr + Size2MiB::SIZE;
+        if let Some(level_3) = child_table(offset, &level_4[addr.p4_index()]) {
+            if let Some(level_2) =
+                child_table(offset, &level_3[addr.p3_index()])
+            {
+                free_table_if_empty(
+                    offset,
+                    &mut level_2[addr.p2_index()],
+                    addr,
+                    frame_deallocator,
+                );
+            }
+            // the level 2 table is done with once its last entry in the
+            // range has been visited
+            if next.is_aligned(Size1GiB::SIZE) || next > last {
+                free_table_if_empty(
+                    offset,
+                    &mut level_3[addr.p3_index()],
+                    addr,
+                    frame_deallocator,
+                );
+            }
+        }
+        addr = next;
+    }
+}
+
+/// Returns the table `entry` points to, unless it is not present or maps a
+/// huge page.
+fn child_table(
+    offset: VirtAddr,
+    entry: &PageTableEntry,
+) -> Option<&'static mut PageTable> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT)
+        || flags.contains(PageTableFlags::HUGE_PAGE)
+    {
+        return None;
+    }
+    Some(unsafe { &mut *(offset + entry.addr().as_u64()).as_mut_ptr() })
+}
+
+/// Free the table `entry` points to and clear the entry if the table has no
+/// used entries.
+///
+/// # Arguments
+/// * `offset` - The offset of the physical memory mapping.
+/// * `entry` - The entry pointing to the table.
+/// * `addr` - An address covered by the entry, for flushing the TLB.
+/// * `frame_deallocator` - The allocator to return the table frame to.
+fn free_table_if_empty<A>(
+    offset: VirtAddr,
+    entry: &mut PageTableEntry,
+    addr: VirtAddr,
+    frame_deallocator: &mut A,
+) where
+    A: FrameDeallocator<Size4KiB>,
+{
+    let Some(table) = child_table(offset, entry) else {
+        return;
+    };
+    if table.iter().any(|entry| !entry.is_unused()) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    entry.set_unused();
+    // drops paging structure cache entries referencing the table
+    tlb::flush(addr);
+    unsafe { frame_deallocator.deallocate_frame(frame) };
+}
+
+/// Returns the size of the address range covered by an entry of a page table
+/// at `level`.
+fn level_size(level: u8) -> u64 {
+    4096 << (9 * (level as u64 - 1))
 }
 
 /// Get a mutable ptr to the level 4 table.
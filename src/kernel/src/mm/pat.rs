@@ -0,0 +1,106 @@
+//! Page Attribute Table
+//!
+//! The memory type of a page is selected by its `PWT`, `PCD` and `PAT` bits,
+//! which together index the eight entries of the `IA32_PAT` MSR. The power-on
+//! table has no write-combining entry, so [`init`] programs the table as
+//!
+//! | index | `PCD` | `PWT` | type            |
+//! |-------|-------|-------|-----------------|
+//! | 0     | 0     | 0     | write-back      |
+//! | 1     | 0     | 1     | write-combining |
+//! | 2     | 1     | 0     | write-through   |
+//! | 3     | 1     | 1     | uncached        |
+//!
+//! with entries 4 to 7 repeating entries 0 to 3. Every memory type is thus
+//! reachable without the `PAT` bit, whose position differs between 4 KiB and
+//! huge pages.
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+};
+
+/// The `IA32_PAT` MSR.
+const IA32_PAT: u32 = 0x277;
+
+/// The memory type encodings used in the PAT.
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+
+/// The table programmed by [`init`], see the module documentation.
+const PAT_VALUE: u64 = {
+    let low = WRITE_BACK
+        | WRITE_COMBINING << 8
+        | WRITE_THROUGH << 16
+        | UNCACHEABLE << 24;
+    low | low << 32
+};
+
+/// Whether [`init`] programmed the PAT.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if the CPU supports the PAT.
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 16) != 0
+}
+
+/// Returns `true` if the PAT has been programmed with the kernel's layout.
+///
+/// Until then, page table flags select the power-on memory types, which have
+/// no write-combining.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Program the PAT with the kernel's layout, if the CPU supports it.
+///
+/// Follows the sequence the Intel SDM gives for changing memory types: caches
+/// are disabled and flushed around the MSR write and the TLB is flushed
+/// afterwards. Must be called before any mapping relies on the new memory
+/// types. The PAT is per CPU, so this only programs the calling CPU.
+pub fn init() {
+    if !is_supported() {
+        log::warn!("PAT not supported, write-combining is unavailable");
+        return;
+    }
+
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(
+            (cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH,
+        );
+        asm!("wbinvd", options(nostack));
+
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+
+        asm!("wbinvd", options(nostack));
+        flush_tlb();
+        Cr0::write(cr0);
+    });
+
+    ENABLED.store(true, Ordering::Relaxed);
+    log::info!("PAT programmed, write-combining available");
+}
+
+/// Flush the whole TLB, including global pages.
+fn flush_tlb() {
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        // toggling global pages off and on flushes them as well
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        tlb::flush_all();
+    }
+}
@@ -0,0 +1,164 @@
+//! Kernel stacks
+//!
+//! Stacks are allocated from the kernel virtual region (see [`super::vmm`])
+//! with an unmapped guard page directly below them. Running off the bottom of
+//! a stack faults on the guard page, which is reported as a stack overflow.
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{
+    frame_allocator::FRAME_ALLOCATOR,
+    guard::{self, GuardKind},
+    paging, vmm,
+};
+
+/// The size of the unmapped guard region below every stack.
+pub const STACK_GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+/// An error that occurred while allocating a stack.
+#[derive(Debug)]
+pub enum StackError {
+    /// The kernel virtual region is exhausted.
+    OutOfVirtualMemory,
+    /// Mapping a page failed.
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        StackError::Map(error)
+    }
+}
+
+/// A kernel stack with a guard page below it.
+///
+/// Dropping the stack unmaps it and frees its frames.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The first address of the guard region.
+    guard: VirtAddr,
+    /// The size of the usable stack in bytes.
+    size: u64,
+}
+
+impl KernelStack {
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + STACK_GUARD_SIZE
+    }
+
+    /// Returns the (exclusive) end of the stack, which is the initial stack
+    /// pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + self.size
+    }
+
+    /// Returns the size of the usable stack in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the pages backing the stack.
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.bottom());
+        let end = Page::containing_address(self.top() - 1u64);
+        Page::range_inclusive(start, end)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        guard::unregister(self.guard);
+
+        let mut mapper = paging::mapper();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for page in self.pages() {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+                // allocation failed before this page was mapped
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("failed to unmap stack page: {error:?}"),
+            }
+        }
+        drop(frame_allocator);
+        drop(mapper);
+
+        vmm::deallocate(self.guard, STACK_GUARD_SIZE + self.size);
+    }
+}
+
+/// Allocate a kernel stack.
+///
+/// # Arguments
+/// * `size` - The size of the stack in bytes, rounded up to whole pages.
+/// * `name` - The name of the stack, used in stack overflow reports.
+pub fn allocate_stack(
+    size: u64,
+    name: &'static str,
+) -> Result<KernelStack, StackError> {
+    let size = x86_64::align_up(size, Size4KiB::SIZE);
+    let guard = vmm::allocate(STACK_GUARD_SIZE + size, Size4KiB::SIZE)
+        .ok_or(StackError::OutOfVirtualMemory)?;
+    guard::register(guard, STACK_GUARD_SIZE, GuardKind::StackOverflow, name);
+    let stack = KernelStack { guard, size };
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let mut mapper = paging::mapper();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for page in stack.pages() {
+        // on failure, dropping the stack unmaps what was mapped so far
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            drop(frame_allocator);
+            drop(mapper);
+            return Err(MapToError::FrameAllocationFailed.into());
+        };
+        let flush =
+            unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) };
+        match flush {
+            Ok(flush) => flush.flush(),
+            Err(error) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                drop(frame_allocator);
+                drop(mapper);
+                return Err(error.into());
+            }
+        }
+    }
+    drop(frame_allocator);
+    drop(mapper);
+
+    Ok(stack)
+}
+
+/// Register the guard page below the stack the bootloader handed over.
+///
+/// The bootloader leaves the page directly below the boot stack unmapped.
+/// Walks down from the current stack pointer to the first unmapped page and
+/// registers it, so that overflowing the boot stack is reported as well.
+pub fn register_boot_stack_guard() {
+    let stack_pointer: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) stack_pointer) };
+
+    let mut page =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(stack_pointer));
+    while paging::walk(page.start_address()).is_mapped() {
+        page -= 1;
+    }
+    guard::register(
+        page.start_address(),
+        STACK_GUARD_SIZE,
+        GuardKind::StackOverflow,
+        "boot stack",
+    );
+}
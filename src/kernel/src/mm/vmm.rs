@@ -0,0 +1,192 @@
+//! Kernel virtual address space allocator
+//!
+//! Hands out page aligned ranges of kernel virtual address space from a
+//! dedicated region, similar to Linux's vmalloc area. The region is a whole
+//! level 4 page table entry in the upper half that is unused at boot, so it
+//! can never collide with the kernel image, the heap or the bootloader's
+//! mappings. Every range is followed by an unmapped guard page.
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+
+use spin::Once;
+use x86_64::{
+    align_up,
+    structures::paging::{
+        FrameAllocator, PageSize, PageTable, PageTableFlags, PageTableIndex,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{frame_allocator::FRAME_ALLOCATOR, paging, Locked};
+
+/// The size of the address space covered by a single level 4 entry.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39; // 512 GiB
+
+/// The size of the unmapped guard page following every range.
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+
+/// The kernel's virtual range allocator, set up by [`init`].
+static KERNEL_RANGES: Once<Locked<VirtualRangeAllocator>> = Once::new();
+
+/// An allocator for ranges of virtual address space.
+///
+/// Free ranges are kept in a map from start address to size and are merged
+/// with their neighbours when a range is returned.
+pub struct VirtualRangeAllocator {
+    /// The first address of the managed region.
+    start: VirtAddr,
+    /// The end (exclusive) of the managed region.
+    end: VirtAddr,
+    /// The free ranges, keyed by start address.
+    free: BTreeMap<u64, u64>,
+}
+
+impl VirtualRangeAllocator {
+    /// Create an allocator managing `[start, end)`.
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(start.as_u64(), end - start);
+        VirtualRangeAllocator { start, end, free }
+    }
+
+    /// Returns `true` if `addr` lies within the managed region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Allocate a range of `size` bytes whose start is aligned to `align`.
+    ///
+    /// The size is rounded up to whole pages and an unmapped guard page is
+    /// reserved after the range.
+    ///
+    /// # Arguments
+    /// * `size` - The size of the range in bytes.
+    /// * `align` - The alignment of the range. Must be a power of two and at
+    ///   least the page size.
+    ///
+    /// # Returns
+    /// The start address of the range, or `None` if the region is exhausted.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        let align = align.max(Size4KiB::SIZE);
+        let size = align_up(size, Size4KiB::SIZE) + GUARD_SIZE;
+
+        let (&free_start, &free_size, start) =
+            self.free.iter().find_map(|(free_start, free_size)| {
+                let start = align_up(*free_start, align);
+                let end = start.checked_add(size)?;
+                (end <= free_start + free_size)
+                    .then_some((free_start, free_size, start))
+            })?;
+
+        self.free.remove(&free_start);
+        if start > free_start {
+            self.free.insert(free_start, start - free_start);
+        }
+        let free_end = free_start + free_size;
+        if start + size < free_end {
+            self.free.insert(start + size, free_end - (start + size));
+        }
+
+        Some(VirtAddr::new(start))
+    }
+
+    /// Return a range to the allocator.
+    ///
+    /// # Arguments
+    /// * `start` - The start address returned by [`Self::allocate`].
+    /// * `size` - The size passed to [`Self::allocate`].
+    pub fn deallocate(&mut self, start: VirtAddr, size: u64) {
+        let mut start = start.as_u64();
+        let mut size = align_up(size, Size4KiB::SIZE) + GUARD_SIZE;
+
+        // merge with the preceding free range
+        if let Some((&prev_start, &prev_size)) =
+            self.free.range(..start).next_back()
+        {
+            if prev_start + prev_size == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
+                size += prev_size;
+            }
+        }
+        // merge with the following free range
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+
+        self.free.insert(start, size);
+    }
+}
+
+/// Initialize the kernel's virtual range allocator.
+///
+/// Picks the highest unused level 4 entry in the upper half of the kernel's
+/// page table as the region to allocate from, and gives it an empty level 3
+/// table right away. The entry then never changes, so address spaces that
+/// copied the kernel half see all later mappings in the region. Must be called
+/// after the heap is initialized.
+pub fn init() {
+    KERNEL_RANGES.call_once(|| {
+        let mut mapper = paging::mapper();
+        let index = (paging::KERNEL_HALF_FIRST_ENTRY..511)
+            .rev()
+            .find(|&index| mapper.level_4_table()[index].is_unused())
+            .expect("no free level 4 entry for the kernel virtual region");
+        let start = VirtAddr::new_truncate(index as u64 * LEVEL_4_ENTRY_SIZE);
+        debug_assert_eq!(start.p4_index(), PageTableIndex::new(index as u16));
+
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .allocate_frame()
+            .expect("no frame for the kernel virtual region's level 3 table");
+        unsafe {
+            let table: *mut PageTable = (mapper.phys_offset()
+                + frame.start_address().as_u64())
+            .as_mut_ptr();
+            table.write(PageTable::new());
+        }
+        mapper.level_4_table_mut()[index].set_frame(
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        log::info!(
+            "Kernel virtual region: {:#x}..{:#x}",
+            start.as_u64(),
+            start.as_u64() + LEVEL_4_ENTRY_SIZE
+        );
+        Locked::new(VirtualRangeAllocator::new(
+            start,
+            start + LEVEL_4_ENTRY_SIZE,
+        ))
+    });
+}
+
+/// Allocate a range of kernel virtual address space.
+///
+/// See [`VirtualRangeAllocator::allocate`].
+///
+/// # Panics
+/// Panics if [`init`] has not been called yet.
+pub fn allocate(size: u64, align: u64) -> Option<VirtAddr> {
+    kernel_ranges().lock().allocate(size, align)
+}
+
+/// Return a range of kernel virtual address space.
+///
+/// See [`VirtualRangeAllocator::deallocate`].
+///
+/// # Panics
+/// Panics if [`init`] has not been called yet.
+pub fn deallocate(start: VirtAddr, size: u64) {
+    kernel_ranges().lock().deallocate(start, size);
+}
+
+/// Returns the kernel's virtual range allocator.
+fn kernel_ranges() -> &'static Locked<VirtualRangeAllocator> {
+    KERNEL_RANGES
+        .get()
+        .expect("kernel virtual region not initialized")
+}
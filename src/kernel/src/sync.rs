@@ -0,0 +1,134 @@
+//! Synchronization primitives for state shared with interrupt handlers
+//!
+//! A plain spinlock deadlocks if an interrupt handler tries to take it while
+//! the interrupted code holds it. [`IrqSafeMutex`] disables interrupts while
+//! it is held, so it is the lock to use for anything interrupt handlers
+//! touch. Interrupt handlers mark themselves with
+//! [`InterruptContext::enter`], and debug builds assert that no plain
+//! [`Locked`](crate::mm::Locked) lock is taken in interrupt context.
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::MutexGuard;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::stats::{self, MAX_CPUS};
+
+/// The number of interrupt handlers running on each CPU.
+static INTERRUPT_DEPTH: [AtomicUsize; MAX_CPUS] =
+    [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Marks the calling CPU as running an interrupt handler until dropped.
+pub struct InterruptContext {
+    /// The CPU the handler runs on.
+    cpu: usize,
+}
+
+impl InterruptContext {
+    /// Mark the calling CPU as running an interrupt handler.
+    ///
+    /// Called at the start of asynchronous interrupt handlers. Exceptions
+    /// are raised by the code they interrupt, so they are not marked.
+    pub fn enter() -> Self {
+        let cpu = stats::current_cpu().min(MAX_CPUS - 1);
+        INTERRUPT_DEPTH[cpu].fetch_add(1, Ordering::Relaxed);
+        InterruptContext { cpu }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH[self.cpu].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` if the calling CPU is running an interrupt handler.
+pub fn in_interrupt() -> bool {
+    // handlers run with interrupts disabled, which saves the CPU lookup
+    if interrupts::are_enabled() {
+        return false;
+    }
+    let cpu = stats::current_cpu().min(MAX_CPUS - 1);
+    INTERRUPT_DEPTH[cpu].load(Ordering::Relaxed) != 0
+}
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// The previous interrupt state is restored when the guard is dropped, so
+/// locks can be nested and taken inside interrupt handlers.
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a new IrqSafeMutex instance.
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    /// Disable interrupts and lock the value.
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    /// Try to lock the value without spinning.
+    ///
+    /// Returns `None` if the value is already locked.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+            }),
+            None => {
+                if interrupts_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The guard of a locked [`IrqSafeMutex`].
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was taken.
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts can arrive again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
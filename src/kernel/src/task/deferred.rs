@@ -0,0 +1,114 @@
+//! Deferred interrupt work
+//!
+//! Interrupt handlers run with interrupts disabled and must neither block
+//! nor allocate, so they should only do what cannot wait and [`defer`] the
+//! rest. Deferred work is run by the task returned from [`run`], which the
+//! executor runs with high priority (see
+//! [`super::executor::Executor::spawn_high_priority`]) and with interrupts
+//! enabled.
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+
+/// The maximum number of queued work items.
+const QUEUE_CAPACITY: usize = 256;
+
+static QUEUE: OnceCell<ArrayQueue<Work>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// A queued work item: a function and the argument to call it with.
+#[derive(Clone, Copy)]
+struct Work {
+    /// The function to call.
+    function: fn(u64),
+    /// The argument to pass.
+    argument: u64,
+}
+
+/// An error that occurred while deferring work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    /// The deferred work task has not been created yet.
+    Uninitialized,
+    /// Too much work is queued already.
+    QueueFull,
+}
+
+impl fmt::Display for DeferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeferError::Uninitialized => {
+                write!(f, "deferred work queue uninitialized")
+            }
+            DeferError::QueueFull => write!(f, "deferred work queue full"),
+        }
+    }
+}
+
+/// Queue `function(argument)` to be run by the deferred work task.
+///
+/// Does not block or allocate, so it can be called from interrupt handlers.
+/// Work items run in the order they were queued.
+///
+/// # Arguments
+/// * `function` - The function to call
+/// * `argument` - The argument to pass, e.g. a byte read from a device
+pub fn defer(function: fn(u64), argument: u64) -> Result<(), DeferError> {
+    let queue = QUEUE.try_get().map_err(|_| DeferError::Uninitialized)?;
+    queue
+        .push(Work { function, argument })
+        .map_err(|_| DeferError::QueueFull)?;
+    WAKER.wake();
+    Ok(())
+}
+
+/// Returns the task that runs the deferred work (should only be called
+/// once).
+///
+/// # Example
+/// ```no_run
+/// let mut executor = executor::Executor::new();
+/// executor.spawn_high_priority(Task::new(deferred::run()));
+/// executor.run();
+/// ```
+pub fn run() -> impl Future<Output = ()> {
+    QUEUE
+        .try_init_once(|| ArrayQueue::new(QUEUE_CAPACITY))
+        .expect("deferred::run should only be called once");
+    DeferredWork { _private: () }
+}
+
+/// The future running the deferred work, never completes.
+struct DeferredWork {
+    _private: (),
+}
+
+impl Future for DeferredWork {
+    type Output = ();
+
+    /// Run all queued work items
+    ///
+    /// Registers the current task to be woken up when more work is queued.
+    ///
+    /// # Arguments
+    /// * `cx` - The current task's context
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let queue = QUEUE
+            .try_get()
+            .expect("deferred work queue not initialized");
+
+        WAKER.register(cx.waker());
+        // work queued after this point wakes the task again
+        while let Some(work) = queue.pop() {
+            (work.function)(work.argument);
+        }
+        Poll::Pending
+    }
+}
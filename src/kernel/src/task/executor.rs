@@ -1,7 +1,11 @@
 //! Task executor
 extern crate alloc;
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    task::Wake,
+};
 use core::task::{Context, Poll, Waker};
 
 use crossbeam_queue::ArrayQueue;
@@ -9,11 +13,18 @@ use crossbeam_queue::ArrayQueue;
 use super::{Task, TaskId};
 
 /// Task executor that drives tasks to completion
+///
+/// High priority tasks, such as the deferred interrupt work (see
+/// [`super::deferred`]), run before any other ready task.
 pub struct Executor {
     /// Binary tree of tasks
     tasks: BTreeMap<TaskId, Task>,
+    /// IDs of the high priority tasks
+    high_priority_tasks: BTreeSet<TaskId>,
     /// Queue of tasks ready to run
     task_queue: Arc<ArrayQueue<TaskId>>,
+    /// Queue of high priority tasks ready to run
+    high_priority_queue: Arc<ArrayQueue<TaskId>>,
     /// Cache of wakers for tasks
     waker_cache: BTreeMap<TaskId, Waker>,
 }
@@ -23,7 +34,9 @@ impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
+            high_priority_tasks: BTreeSet::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
+            high_priority_queue: Arc::new(ArrayQueue::new(100)),
             waker_cache: BTreeMap::new(),
         }
     }
@@ -46,6 +59,26 @@ impl Executor {
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    /// Spawn a new high priority task
+    ///
+    /// Whenever the task is ready, it runs before any other task. It should
+    /// therefore only do short pieces of work between awaits.
+    ///
+    /// # Safety
+    /// * panics if the task ID is already in the executor
+    /// * panics if the task queue is full
+    ///
+    /// # Arguments
+    /// * `task` - [`Task`] to spawn
+    pub fn spawn_high_priority(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.high_priority_tasks.insert(task_id);
+        self.high_priority_queue.push(task_id).expect("queue full");
+    }
+
     /// Run the executor
     ///
     /// Continuously runs tasks until there are no more tasks to run
@@ -62,23 +95,34 @@ impl Executor {
         // destructure `self` to avoid borrow checker errors
         let Self {
             tasks,
+            high_priority_tasks,
             task_queue,
+            high_priority_queue,
             waker_cache,
         } = self;
 
-        while let Some(task_id) = task_queue.pop() {
+        // high priority tasks are checked for before every task
+        while let Some(task_id) =
+            high_priority_queue.pop().or_else(|| task_queue.pop())
+        {
             let task = match tasks.get_mut(&task_id) {
                 Some(task) => task,
                 None => continue, // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let waker = waker_cache.entry(task_id).or_insert_with(|| {
+                let queue = if high_priority_tasks.contains(&task_id) {
+                    high_priority_queue.clone()
+                } else {
+                    task_queue.clone()
+                };
+                TaskWaker::new(task_id, queue)
+            });
             let mut context = Context::from_waker(waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
                     tasks.remove(&task_id);
+                    high_priority_tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
                 }
                 Poll::Pending => {}
@@ -94,7 +138,7 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && self.high_priority_queue.is_empty() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -10,6 +10,7 @@ use core::{
     task::{Context, Poll},
 };
 
+pub mod deferred;
 pub mod executor;
 
 /// A unique identifier for a task.
@@ -0,0 +1,470 @@
+//! Running code in user mode
+//!
+//! [`run`] enters ring 3 with `iretq` and returns once the user code leaves
+//! it again. User code runs on the active [`AddressSpace`] with interrupts
+//! enabled. Interrupts arriving in user mode switch to the kernel stack in
+//! the TSS (see [`crate::interrupts::gdt::init_cpu`]) and return to the user
+//! code. Exceptions raised by the user code do not panic like kernel
+//! exceptions: their handlers call [`exit_to_kernel`], which abandons the
+//! user code and resumes the kernel where it called [`run`]. User code
+//! enters the kernel on purpose through system calls (see [`syscall`]).
+pub mod syscall;
+
+use core::{
+    arch::naked_asm,
+    fmt,
+    mem::offset_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::{InterruptStackFrame, PageFaultErrorCode},
+        paging::{
+            mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB,
+        },
+    },
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{
+    interrupts::{
+        gdt,
+        stats::{self, MAX_CPUS},
+    },
+    mm::{address_space::AddressSpace, paging},
+    sync::IrqSafeMutex,
+};
+
+/// The address user programs are loaded at by [`load_program`].
+pub const USER_CODE_START: u64 = 0x40_0000;
+
+/// The top of the stack [`load_program`] maps for user programs.
+pub const USER_STACK_TOP: u64 = 0x7fff_ffff_0000;
+
+/// The size of the stack [`load_program`] maps for user programs.
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+
+/// The `RFLAGS` user code starts with: interrupts enabled and the reserved
+/// bit 1 set.
+const USER_RFLAGS: u64 = 0x202;
+
+/// The `RFLAGS` bits user code may change: the arithmetic flags and the
+/// direction flag.
+const USER_RFLAGS_MASK: u64 = 0xcd5;
+
+/// The end of the lower half, user code and stacks lie below.
+const USER_HALF_END: u64 = 0x0000_8000_0000_0000;
+
+/// The kernel stack pointer saved by [`enter_user`] on each CPU, indexed by
+/// [`stats::cpu_index`], zero while the CPU is not running user code.
+static KERNEL_RSP: [AtomicU64; MAX_CPUS] =
+    [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Why user code left user mode and, if it can be resumed, its context.
+type SavedExit = (UserExit, Option<UserContext>);
+
+/// The exit of the user code on each CPU, indexed by [`stats::cpu_index`],
+/// set by [`exit_to_kernel`] and [`yield_to_kernel`].
+static EXITS: IrqSafeMutex<[Option<SavedExit>; MAX_CPUS]> =
+    IrqSafeMutex::new([None; MAX_CPUS]);
+
+/// The registers of user code: where it starts or where it continues after
+/// yielding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// The instruction pointer.
+    pub rip: u64,
+    /// The stack pointer.
+    pub rsp: u64,
+    /// The flags, only the bits in [`USER_RFLAGS_MASK`] are used.
+    pub rflags: u64,
+}
+
+impl UserContext {
+    /// Returns the context of user code starting at `entry`, with every
+    /// other register zeroed.
+    ///
+    /// # Arguments
+    /// * `entry` - The address of the first user instruction
+    /// * `stack_top` - The initial user stack pointer
+    pub fn new(entry: VirtAddr, stack_top: VirtAddr) -> Self {
+        UserContext {
+            rip: entry.as_u64(),
+            rsp: stack_top.as_u64(),
+            rflags: USER_RFLAGS,
+            ..Default::default()
+        }
+    }
+}
+
+/// Why user code returned to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The user code raised an exception.
+    Exception {
+        /// The name of the exception.
+        name: &'static str,
+        /// The address of the faulting instruction.
+        instruction_pointer: VirtAddr,
+    },
+    /// The user code called the `exit` system call.
+    Exit(i32),
+    /// The user code called the `yield` system call, it can be resumed by
+    /// passing the updated context to [`run`] again.
+    Yield,
+    /// The user code accessed an address it may not access.
+    PageFault {
+        /// The accessed address.
+        address: VirtAddr,
+        /// The error code of the page fault.
+        error_code: PageFaultErrorCode,
+        /// The address of the faulting instruction.
+        instruction_pointer: VirtAddr,
+    },
+}
+
+impl fmt::Display for UserExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserExit::Exception {
+                name,
+                instruction_pointer,
+            } => write!(f, "{name} at {:#x}", instruction_pointer.as_u64()),
+            UserExit::Exit(code) => write!(f, "exited with code {code}"),
+            UserExit::Yield => write!(f, "yielded"),
+            UserExit::PageFault {
+                address,
+                error_code,
+                instruction_pointer,
+            } => write!(
+                f,
+                "PAGE FAULT accessing {:#x} at {:#x} ({error_code:?})",
+                address.as_u64(),
+                instruction_pointer.as_u64()
+            ),
+        }
+    }
+}
+
+/// Returns `true` if the interrupted code ran in user mode.
+///
+/// # Arguments
+/// * `stack_frame` - The stack frame of the interrupt
+pub fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Run user code until it returns to the kernel.
+///
+/// Interrupts are enabled while the user code runs, the previous interrupt
+/// state is restored afterwards.
+///
+/// # Arguments
+/// * `context` - The registers to start the user code with. Updated if the user
+///   code yields, so that it continues where it left off when passed to this
+///   function again.
+///
+/// # Returns
+/// The reason the user code returned.
+///
+/// # Safety
+/// The user code and stack must be mapped user accessible in the active
+/// address space, and no kernel memory may be. The address space must stay
+/// active until this returns.
+///
+/// # Panics
+/// Panics if the calling CPU is already running user code, or if the
+/// instruction or stack pointer is not in the lower half.
+pub unsafe fn run(context: &mut UserContext) -> UserExit {
+    assert!(
+        context.rip < USER_HALF_END && context.rsp <= USER_HALF_END,
+        "user code must run in the lower half"
+    );
+    let cpu = stats::cpu_index();
+    let (code_selector, data_selector) = gdt::user_selectors();
+    let interrupts_enabled = interrupts::are_enabled();
+    // the exit handlers must not find a half set up CPU
+    interrupts::disable();
+    assert_eq!(
+        KERNEL_RSP[cpu].load(Ordering::Relaxed),
+        0,
+        "CPU {cpu} is already running user code"
+    );
+
+    let mut entry_context = *context;
+    entry_context.rflags = (context.rflags & USER_RFLAGS_MASK) | USER_RFLAGS;
+    unsafe {
+        enter_user(
+            &entry_context,
+            code_selector.0.into(),
+            data_selector.0.into(),
+            KERNEL_RSP[cpu].as_ptr(),
+        );
+    }
+
+    // system calls return here with interrupts enabled
+    interrupts::disable();
+    let (exit, saved_context) = EXITS.lock()[cpu]
+        .take()
+        .expect("user code returned without an exit reason");
+    if let Some(saved_context) = saved_context {
+        *context = saved_context;
+    }
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+    exit
+}
+
+/// Leave the user code running on the calling CPU and resume the kernel
+/// where it called [`run`], which returns `exit`.
+///
+/// Called by the exception handlers when the exception was raised in user
+/// mode, with interrupts disabled.
+///
+/// # Arguments
+/// * `exit` - The reason the user code returns
+///
+/// # Panics
+/// Panics if the calling CPU is not running user code.
+pub fn exit_to_kernel(exit: UserExit) -> ! {
+    let cpu = stats::cpu_index();
+    let kernel_rsp = KERNEL_RSP[cpu].swap(0, Ordering::Relaxed);
+    assert_ne!(kernel_rsp, 0, "CPU {cpu} is not running user code");
+    EXITS.lock()[cpu] = Some((exit, None));
+    unsafe { resume_kernel(kernel_rsp) }
+}
+
+/// Like [`exit_to_kernel`] with [`UserExit::Yield`], but the context passed
+/// to [`run`] is replaced with `context` so that the user code can be
+/// resumed.
+///
+/// # Arguments
+/// * `context` - The registers to resume the user code with
+///
+/// # Panics
+/// Panics if the calling CPU is not running user code.
+pub fn yield_to_kernel(context: UserContext) -> ! {
+    let cpu = stats::cpu_index();
+    let kernel_rsp = KERNEL_RSP[cpu].swap(0, Ordering::Relaxed);
+    assert_ne!(kernel_rsp, 0, "CPU {cpu} is not running user code");
+    EXITS.lock()[cpu] = Some((UserExit::Yield, Some(context)));
+    unsafe { resume_kernel(kernel_rsp) }
+}
+
+/// Save the callee-saved registers and the kernel stack pointer, then
+/// `iretq` to ring 3 with the registers of `context`.
+///
+/// Returns when [`resume_kernel`] is called with the saved stack pointer.
+///
+/// # Arguments
+/// * `context` - The registers to load (`rdi`)
+/// * `code_selector` - The user code segment selector (`rsi`)
+/// * `data_selector` - The user data segment selector (`rdx`)
+/// * `kernel_rsp` - Where to save the kernel stack pointer (`rcx`)
+#[unsafe(naked)]
+unsafe extern "sysv64" fn enter_user(
+    context: *const UserContext,
+    code_selector: u64,
+    data_selector: u64,
+    kernel_rsp: *mut u64,
+) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rcx], rsp",
+        // the interrupt return frame: SS, RSP, RFLAGS, CS and RIP
+        "push rdx",
+        "push qword ptr [rdi + {rsp}]",
+        "push qword ptr [rdi + {rflags}]",
+        "push rsi",
+        "push qword ptr [rdi + {rip}]",
+        "mov rax, [rdi + {rax}]",
+        "mov rbx, [rdi + {rbx}]",
+        "mov rcx, [rdi + {rcx}]",
+        "mov rdx, [rdi + {rdx}]",
+        "mov rsi, [rdi + {rsi}]",
+        "mov rbp, [rdi + {rbp}]",
+        "mov r8, [rdi + {r8}]",
+        "mov r9, [rdi + {r9}]",
+        "mov r10, [rdi + {r10}]",
+        "mov r11, [rdi + {r11}]",
+        "mov r12, [rdi + {r12}]",
+        "mov r13, [rdi + {r13}]",
+        "mov r14, [rdi + {r14}]",
+        "mov r15, [rdi + {r15}]",
+        "mov rdi, [rdi + {rdi}]",
+        "iretq",
+        rax = const offset_of!(UserContext, rax),
+        rbx = const offset_of!(UserContext, rbx),
+        rcx = const offset_of!(UserContext, rcx),
+        rdx = const offset_of!(UserContext, rdx),
+        rsi = const offset_of!(UserContext, rsi),
+        rdi = const offset_of!(UserContext, rdi),
+        rbp = const offset_of!(UserContext, rbp),
+        r8 = const offset_of!(UserContext, r8),
+        r9 = const offset_of!(UserContext, r9),
+        r10 = const offset_of!(UserContext, r10),
+        r11 = const offset_of!(UserContext, r11),
+        r12 = const offset_of!(UserContext, r12),
+        r13 = const offset_of!(UserContext, r13),
+        r14 = const offset_of!(UserContext, r14),
+        r15 = const offset_of!(UserContext, r15),
+        rip = const offset_of!(UserContext, rip),
+        rsp = const offset_of!(UserContext, rsp),
+        rflags = const offset_of!(UserContext, rflags),
+    )
+}
+
+/// Switch to a kernel stack pointer saved by [`enter_user`] and return from
+/// [`enter_user`] on it.
+///
+/// # Arguments
+/// * `kernel_rsp` - The saved stack pointer (`rdi`)
+#[unsafe(naked)]
+unsafe extern "sysv64" fn resume_kernel(kernel_rsp: u64) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "cld",
+        "ret",
+    )
+}
+
+/// Map a user program into an address space: `code` at
+/// [`USER_CODE_START`], read-only and executable, and a writable stack of
+/// [`USER_STACK_SIZE`] bytes below [`USER_STACK_TOP`].
+///
+/// # Arguments
+/// * `address_space` - The address space to map the program into
+/// * `code` - The machine code of the program
+///
+/// # Returns
+/// The initial context of the program, to be passed to [`run`].
+pub fn load_program(
+    address_space: &mut AddressSpace,
+    code: &[u8],
+) -> Result<UserContext, MapToError<Size4KiB>> {
+    let entry = VirtAddr::new(USER_CODE_START);
+    for (index, chunk) in code.chunks(Size4KiB::SIZE as usize).enumerate() {
+        let page =
+            Page::containing_address(entry + index as u64 * Size4KiB::SIZE);
+        let frame =
+            address_space.map_user_page(page, PageTableFlags::empty())?;
+        // the page is not writable through the user mapping, so the code is
+        // copied through the physical memory window
+        unsafe {
+            let frame_ptr: *mut u8 = (paging::physical_memory_offset()
+                + frame.start_address().as_u64())
+            .as_mut_ptr();
+            frame_ptr.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+        }
+    }
+
+    let stack_top = VirtAddr::new(USER_STACK_TOP);
+    address_space.map_user_range(
+        stack_top - USER_STACK_SIZE,
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    Ok(UserContext::new(entry, stack_top))
+}
+
+/// Test programs for [`run_test`], each ending in an exception or `exit`.
+const TEST_PROGRAMS: &[(&str, &[u8])] = &[
+    (
+        "system calls",
+        &[
+            0x48, 0x8d, 0x3d, 0x24, 0x00, 0x00,
+            0x00, // lea rdi, [rip + msg]
+            0xbe, 0x11, 0x00, 0x00, 0x00, // mov esi, 17
+            0x31, 0xc0, // xor eax, eax (write)
+            0x0f, 0x05, // syscall
+            0x49, 0x89, 0xc4, // mov r12, rax
+            0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2 (yield)
+            0x0f, 0x05, // syscall
+            0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3 (get ticks)
+            0x0f, 0x05, // syscall
+            0x4c, 0x89, 0xe7, // mov rdi, r12
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (exit)
+            0x0f, 0x05, // syscall
+            // msg: "hello from ring 3"
+            b'h', b'e', b'l', b'l', b'o', b' ', b'f', b'r', b'o', b'm', b' ',
+            b'r', b'i', b'n', b'g', b' ', b'3',
+        ],
+    ),
+    (
+        "breakpoint",
+        &[
+            0x48, 0xc7, 0xc0, 0x34, 0x12, 0x00, 0x00, // mov rax, 0x1234
+            0x50, // push rax
+            0x5b, // pop rbx
+            0xcc, // int3
+        ],
+    ),
+    ("privileged instruction", &[0xf4]), // hlt
+    (
+        "kernel memory access",
+        &[
+            0x48, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xff,
+            0xff, // mov rax, 0xffff_8000_0000_0000
+            0x48, 0x8b, 0x00, // mov rax, [rax]
+        ],
+    ),
+];
+
+/// Run each test program in a fresh address space and log how it returned
+/// to the kernel. Programs that yield are resumed.
+///
+/// # Arguments
+/// * `level` - The log level to use
+pub fn run_test(level: log::Level) {
+    for (name, code) in TEST_PROGRAMS {
+        let result = AddressSpace::new().and_then(|mut address_space| {
+            let mut context = load_program(&mut address_space, code)?;
+            unsafe { address_space.activate() };
+            let exit = loop {
+                match unsafe { run(&mut context) } {
+                    UserExit::Yield => log::log!(level, "  {name}: yielded"),
+                    exit => break exit,
+                }
+            };
+            // dropping the address space switches back to the kernel's
+            drop(address_space);
+            Ok(exit)
+        });
+        match result {
+            Ok(exit) => log::log!(level, "  {name}: {exit}"),
+            Err(error) => {
+                log::log!(level, "  {name}: failed to load: {error:?}")
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! System calls
+//!
+//! User code calls into the kernel with the `syscall` instruction: the
+//! system call number goes in `rax`, up to six arguments in `rdi`, `rsi`,
+//! `rdx`, `r10`, `r8` and `r9`, and the result comes back in `rax`. Errors
+//! are returned as a negative [`Errno`], like on Linux. `syscall` itself
+//! clobbers `rcx` and `r11`, every other register is preserved.
+//!
+//! The entry stub switches to the kernel stack of the CPU, saves the user
+//! registers in a [`SyscallFrame`] and calls the handler from [`SYSCALLS`]
+//! with interrupts enabled. Handlers decode their arguments with
+//! [`SyscallFrame::arg`].
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    arch::naked_asm,
+    fmt,
+    mem::offset_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{UserContext, UserExit, USER_HALF_END};
+use crate::{
+    debug::console::OUTPUT_LEVEL,
+    devices::timer,
+    interrupts::{
+        gdt,
+        stats::{self, MAX_CPUS},
+    },
+    mm::paging,
+};
+
+/// Write a buffer to the console: `write(buffer: *const u8, len: usize)`.
+/// Returns the number of bytes written.
+pub const SYS_WRITE: u64 = 0;
+
+/// Leave user mode for good: `exit(code: i32)`. Does not return.
+pub const SYS_EXIT: u64 = 1;
+
+/// Return to the kernel, which may resume the caller later: `yield()`.
+/// Returns 0.
+pub const SYS_YIELD: u64 = 2;
+
+/// Returns the number of timer ticks since boot: `get_ticks()`.
+pub const SYS_GET_TICKS: u64 = 3;
+
+/// The most bytes a single `write` writes.
+const MAX_WRITE_LEN: usize = 1024;
+
+/// The per-CPU data the entry stub finds through the `GS` base.
+#[repr(C)]
+struct CpuLocal {
+    /// The top of the kernel stack system calls run on.
+    kernel_stack: AtomicU64,
+    /// The user stack pointer, saved while switching stacks.
+    user_stack: AtomicU64,
+}
+
+/// The per-CPU data of each CPU, indexed by CPU index (see
+/// [`stats::cpu_index`]).
+static CPU_LOCAL: [CpuLocal; MAX_CPUS] = [const {
+    CpuLocal {
+        kernel_stack: AtomicU64::new(0),
+        user_stack: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+
+/// An error returned by a system call, numbered like on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// A buffer is not accessible to the caller.
+    EFAULT = 14,
+    /// An argument is out of range.
+    EINVAL = 22,
+    /// There is no system call with the requested number.
+    ENOSYS = 38,
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Errno::EFAULT => write!(f, "bad address"),
+            Errno::EINVAL => write!(f, "invalid argument"),
+            Errno::ENOSYS => write!(f, "function not implemented"),
+        }
+    }
+}
+
+/// The result of a system call.
+pub type SyscallResult = Result<u64, Errno>;
+
+/// The user registers saved by the entry stub, in the order they are
+/// pushed, lowest address first.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rbx: u64,
+    /// The system call number, replaced with the result.
+    pub rax: u64,
+    /// The user instruction pointer, saved by `syscall` in `rcx`.
+    pub rip: u64,
+    /// The user flags, saved by `syscall` in `r11`.
+    pub rflags: u64,
+    /// The user stack pointer.
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Decode an argument.
+    ///
+    /// # Arguments
+    /// * `index` - The number of the argument, from 0 to 5
+    ///
+    /// # Panics
+    /// Panics if `index` is greater than 5.
+    pub fn arg<T: SyscallArg>(&self, index: usize) -> Result<T, Errno> {
+        let raw = [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9];
+        T::decode(raw[index])
+    }
+
+    /// Returns the context that resumes the user code after the system
+    /// call, returning `result`.
+    fn context(&self, result: u64) -> UserContext {
+        UserContext {
+            rax: result,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rsp: self.rsp,
+            rflags: self.rflags,
+        }
+    }
+}
+
+/// A type a system call argument register can be decoded into.
+pub trait SyscallArg: Sized {
+    /// Decode the raw register value.
+    fn decode(raw: u64) -> Result<Self, Errno>;
+}
+
+impl SyscallArg for u64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for usize {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw as usize)
+    }
+}
+
+impl SyscallArg for i32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        // 32 bit arguments are passed zero or sign extended
+        i32::try_from(raw as i64)
+            .or_else(|_| u32::try_from(raw).map(|raw| raw as i32))
+            .map_err(|_| Errno::EINVAL)
+    }
+}
+
+/// A pointer into user memory, checked to be in the lower half. The memory
+/// it points to is checked when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserPtr(VirtAddr);
+
+impl SyscallArg for UserPtr {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        if raw >= USER_HALF_END {
+            return Err(Errno::EFAULT);
+        }
+        Ok(UserPtr(VirtAddr::new(raw)))
+    }
+}
+
+impl UserPtr {
+    /// Copy `len` bytes from user memory.
+    ///
+    /// # Returns
+    /// [`Errno::EFAULT`] if any of the bytes is not mapped user accessible
+    /// in the active address space.
+    pub fn read_bytes(self, len: usize) -> Result<Vec<u8>, Errno> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let end = self
+            .0
+            .as_u64()
+            .checked_add(len as u64)
+            .filter(|&end| end <= USER_HALF_END)
+            .ok_or(Errno::EFAULT)?;
+        let first = Page::<Size4KiB>::containing_address(self.0);
+        let last = Page::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last) {
+            let flags = paging::walk(page.start_address()).effective_flags();
+            if !flags
+                .is_some_and(|f| f.contains(PageTableFlags::USER_ACCESSIBLE))
+            {
+                return Err(Errno::EFAULT);
+            }
+        }
+        let bytes =
+            unsafe { core::slice::from_raw_parts(self.0.as_ptr::<u8>(), len) };
+        Ok(bytes.to_vec())
+    }
+}
+
+/// A system call handler.
+pub struct Syscall {
+    /// The name of the system call, for reports.
+    pub name: &'static str,
+    /// The handler, called with the saved user registers.
+    pub handler: fn(&SyscallFrame) -> SyscallResult,
+}
+
+/// The system calls, indexed by number.
+pub static SYSCALLS: [Syscall; 4] = [
+    Syscall {
+        name: "write",
+        handler: sys_write,
+    },
+    Syscall {
+        name: "exit",
+        handler: sys_exit,
+    },
+    Syscall {
+        name: "yield",
+        handler: sys_yield,
+    },
+    Syscall {
+        name: "get_ticks",
+        handler: sys_get_ticks,
+    },
+];
+
+/// `write(buffer, len)`: log up to [`MAX_WRITE_LEN`] bytes as a console
+/// line.
+fn sys_write(frame: &SyscallFrame) -> SyscallResult {
+    let buffer: UserPtr = frame.arg(0)?;
+    let len = frame.arg::<usize>(1)?.min(MAX_WRITE_LEN);
+    let bytes = buffer.read_bytes(len)?;
+    let text = String::from_utf8_lossy(&bytes);
+    log::log!(OUTPUT_LEVEL, "[user] {}", text.trim_end_matches('\n'));
+    Ok(len as u64)
+}
+
+/// `exit(code)`: return to the kernel with [`UserExit::Exit`].
+fn sys_exit(frame: &SyscallFrame) -> SyscallResult {
+    let code: i32 = frame.arg(0)?;
+    super::exit_to_kernel(UserExit::Exit(code));
+}
+
+/// `yield()`: return to the kernel with [`UserExit::Yield`].
+fn sys_yield(frame: &SyscallFrame) -> SyscallResult {
+    super::yield_to_kernel(frame.context(0));
+}
+
+/// `get_ticks()`: returns the timer ticks since boot.
+fn sys_get_ticks(_frame: &SyscallFrame) -> SyscallResult {
+    Ok(timer::get_ticks())
+}
+
+/// Enable the `syscall` instruction on the calling CPU and point it at the
+/// entry stub.
+///
+/// Must be called after the GDT and TSS are loaded, the system calls run on
+/// the kernel stack in the TSS.
+pub fn init() {
+    let local = &CPU_LOCAL[stats::cpu_index()];
+    local.kernel_stack.store(
+        gdt::tss().privilege_stack_table[0].as_u64(),
+        Ordering::Relaxed,
+    );
+    KernelGsBase::write(VirtAddr::from_ptr(local));
+
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .expect("GDT segment order does not match SYSCALL/SYSRET");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    // the entry stub runs with interrupts disabled until it is on the kernel
+    // stack
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS))
+    };
+}
+
+/// Run the system call requested in `frame` and store its result in
+/// `frame.rax`.
+///
+/// Called by [`syscall_entry`] with interrupts disabled.
+extern "sysv64" fn syscall_handler(frame: &mut SyscallFrame) {
+    interrupts::enable();
+    let result = match SYSCALLS.get(frame.rax as usize) {
+        Some(syscall) => (syscall.handler)(frame),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+
+    // sysretq faults in kernel mode on a non-canonical return address,
+    // which a syscall at the very end of the lower half would produce
+    if frame.rip >= USER_HALF_END {
+        super::exit_to_kernel(UserExit::Exception {
+            name: "SYSCALL AT END OF USER HALF",
+            instruction_pointer: VirtAddr::new_truncate(frame.rip),
+        });
+    }
+}
+
+/// The target of the `syscall` instruction.
+///
+/// Switches to the kernel stack found through the `GS` base, saves the user
+/// registers as a [`SyscallFrame`], calls [`syscall_handler`] and returns
+/// with `sysretq`.
+#[unsafe(naked)]
+unsafe extern "sysv64" fn syscall_entry() {
+    naked_asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_stack}]",
+        "swapgs",
+        "push r11",
+        "push rcx",
+        "push rax",
+        "push rbx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        // no interrupts while the stack pointer is switched back
+        "cli",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rbx",
+        "pop rax",
+        "pop rcx",
+        "pop r11",
+        "pop rsp",
+        "sysretq",
+        user_stack = const offset_of!(CpuLocal, user_stack),
+        kernel_stack = const offset_of!(CpuLocal, kernel_stack),
+        handler = sym syscall_handler,
+    )
+}